@@ -0,0 +1,113 @@
+//
+// Copyright 2024-2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::path::Path;
+
+use crate::tar::SimpleTarArchive;
+use crate::zip::SimpleZipArchive;
+
+/// Output archive backends
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ArchiveFormat {
+    #[default]
+    Cbt,
+    Cbz,
+}
+
+impl ArchiveFormat {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "cbt" | "tar" => Ok(Self::Cbt),
+            "cbz" | "zip" => Ok(Self::Cbz),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unknown archive format '{name}'"),
+            )),
+        }
+    }
+
+    pub(crate) fn detect(path: &Path) -> Result<Option<Self>> {
+        let mut header = Vec::with_capacity(512);
+        File::open(path)?.take(512).read_to_end(&mut header)?;
+        if header.starts_with(b"PK\x03\x04") || header.starts_with(b"PK\x05\x06") {
+            Ok(Some(Self::Cbz))
+        } else if header.get(257..262) == Some(b"ustar") {
+            Ok(Some(Self::Cbt))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("cbz") || ext.eq_ignore_ascii_case("zip") => {
+                Self::Cbz
+            }
+            _ => Self::Cbt,
+        }
+    }
+}
+
+pub(crate) enum ArchiveWriter {
+    Tar(SimpleTarArchive),
+    Zip(SimpleZipArchive),
+}
+
+impl ArchiveWriter {
+    pub(crate) fn new(writer: impl Write + 'static, format: ArchiveFormat) -> Self {
+        match format {
+            ArchiveFormat::Cbt => Self::Tar(SimpleTarArchive::new(writer)),
+            ArchiveFormat::Cbz => Self::Zip(SimpleZipArchive::new(writer)),
+        }
+    }
+
+    // Returns the offset of the file data
+    pub(crate) fn write_file<P: AsRef<Path>>(&mut self, path: P, file_name: &str) -> Result<u64> {
+        match self {
+            Self::Tar(tar) => tar.write_file(path, file_name),
+            Self::Zip(zip) => zip.write_file(path, file_name),
+        }
+    }
+
+    pub(crate) fn write_bytes(&mut self, data: &[u8], file_name: &str) -> Result<u64> {
+        match self {
+            Self::Tar(tar) => tar.write_bytes(data, file_name),
+            Self::Zip(zip) => zip.write_bytes(data, file_name),
+        }
+    }
+
+    pub(crate) fn write_dir(&mut self, dir_name: &str) -> Result<()> {
+        match self {
+            Self::Tar(tar) => tar.write_dir(dir_name),
+            Self::Zip(zip) => zip.write_dir(dir_name),
+        }
+    }
+
+    pub(crate) fn finish(self) -> Result<Box<dyn Write>> {
+        match self {
+            Self::Tar(tar) => tar.finish(),
+            Self::Zip(zip) => zip.finish(),
+        }
+    }
+}
@@ -0,0 +1,323 @@
+//
+// Copyright 2024-2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use crate::archive::ArchiveFormat;
+use crate::cache::EncodeCache;
+use crate::comicinfo::ComicInfo;
+use crate::encoder::{Encoder, EncoderSettings, set_idle_io, set_nice};
+use crate::error::CbtResult;
+use crate::inputs::{InputOptions, InputPage, Inputs, NonImagePolicy};
+use crate::signal::check_signal;
+use crate::sort::SortMode;
+use crate::state::StateDir;
+use crate::writer::{CbtEvent, CbtSummary, CbtWriter, EventHandler, FailurePolicy};
+
+/// Whole archive from input paths: collects the pages, configures a CbtWriter
+/// and submits each page to it
+#[derive(Default)]
+pub struct CbtBuilder {
+    paths: Vec<PathBuf>,
+    options: InputOptions,
+    format: Option<ArchiveFormat>,
+    encoder: Encoder,
+    settings: Option<EncoderSettings>,
+    on_error: FailurePolicy,
+    jobs: Option<usize>,
+    threads_per_job: Option<usize>,
+    nice: Option<i32>,
+    idle_io: bool,
+    max_ahead: Option<usize>,
+    min_savings: Option<u8>,
+    cache: Option<EncodeCache>,
+    state_dir: Option<(PathBuf, bool)>,
+    progress: Option<bool>,
+    events: Option<EventHandler>,
+    comic_info: Option<ComicInfo>,
+}
+
+// Event handler is a closure
+impl fmt::Debug for CbtBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CbtBuilder")
+            .field("paths", &self.paths)
+            .field("options", &self.options)
+            .field("format", &self.format)
+            .field("encoder", &self.encoder)
+            .field("settings", &self.settings)
+            .field("on_error", &self.on_error)
+            .field("jobs", &self.jobs)
+            .field("threads_per_job", &self.threads_per_job)
+            .field("nice", &self.nice)
+            .field("idle_io", &self.idle_io)
+            .field("max_ahead", &self.max_ahead)
+            .field("min_savings", &self.min_savings)
+            .field("cache", &self.cache)
+            .field("state_dir", &self.state_dir)
+            .field("progress", &self.progress)
+            .field("events", &self.events.is_some())
+            .field("comic_info", &self.comic_info)
+            .finish()
+    }
+}
+
+impl CbtBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Image files, directories of them or existing archives, in order
+    pub fn input<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.paths.push(path.into());
+        self
+    }
+
+    pub fn inputs<I: IntoIterator<Item = P>, P: Into<PathBuf>>(mut self, paths: I) -> Self {
+        self.paths.extend(paths.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn sort(mut self, mode: SortMode) -> Self {
+        self.options.sort_mode = mode;
+        self
+    }
+
+    pub fn non_images(mut self, policy: NonImagePolicy) -> Self {
+        self.options.non_images = policy;
+        self
+    }
+
+    /// Include subdirectories as chapter directories
+    pub fn recursive(mut self, recursive: bool) -> Self {
+        self.options.recursive = recursive;
+        self
+    }
+
+    /// Archive format, otherwise from the output file extension
+    pub fn format(mut self, format: ArchiveFormat) -> Self {
+        self.format = Some(format);
+        self
+    }
+
+    pub fn encoder(mut self, encoder: Encoder, settings: EncoderSettings) -> Self {
+        self.encoder = encoder;
+        self.settings = Some(settings);
+        self
+    }
+
+    pub fn failure_policy(mut self, policy: FailurePolicy) -> Self {
+        self.on_error = policy;
+        self
+    }
+
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        self.jobs = Some(jobs);
+        self
+    }
+
+    pub fn threads_per_job(mut self, threads: usize) -> Self {
+        self.threads_per_job = Some(threads);
+        self
+    }
+
+    /// Lowered CPU priority (0-19) for this process and the encoders it starts
+    pub fn nice(mut self, nice: i32) -> Self {
+        self.nice = Some(nice);
+        self
+    }
+
+    /// Disk access for this process and its encoders only when otherwise idle
+    /// (Linux)
+    pub fn idle_io(mut self, idle_io: bool) -> Self {
+        self.idle_io = idle_io;
+        self
+    }
+
+    pub fn max_ahead(mut self, pages: usize) -> Self {
+        self.max_ahead = Some(pages);
+        self
+    }
+
+    pub fn keep_smaller(mut self, min_savings: u8) -> Self {
+        self.min_savings = Some(min_savings);
+        self
+    }
+
+    pub fn cache(mut self, cache: EncodeCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Encoded pages are kept in the directory until the archive is done, and
+    /// reused from it when resuming
+    pub fn state_dir<P: Into<PathBuf>>(mut self, dir: P, resume: bool) -> Self {
+        self.state_dir = Some((dir.into(), resume));
+        self
+    }
+
+    /// Progress display on standard error, as a redrawn status line or one line per page
+    pub fn progress(mut self, bar: bool) -> Self {
+        self.progress = Some(bar);
+        self
+    }
+
+    /// Events replace warnings printed to standard error
+    pub fn on_event(mut self, handler: impl FnMut(CbtEvent) + 'static) -> Self {
+        self.events = Some(Box::new(handler));
+        self
+    }
+
+    /// Generated ComicInfo.xml, replacing any found among the inputs
+    pub fn comic_info(mut self, comic_info: ComicInfo) -> Self {
+        self.comic_info = Some(comic_info);
+        self
+    }
+
+    /// Target is only replaced once the archive is complete
+    pub fn create<P: AsRef<Path>>(self, path: P) -> CbtResult<CbtSummary> {
+        let path = path.as_ref();
+        let format = self.format.unwrap_or(ArchiveFormat::from_path(path));
+        let (_, summary) = self.build(false, |pages| {
+            CbtWriter::create(path, format, Self::padding(pages))
+        })?;
+        Ok(summary)
+    }
+
+    /// Archive written as a stream, returning the writer once done
+    pub fn write(self, writer: impl Write + 'static) -> CbtResult<(Box<dyn Write>, CbtSummary)> {
+        let format = self.format.unwrap_or_default();
+        self.build(false, |pages| {
+            CbtWriter::new(writer, format, Self::padding(pages))
+        })
+    }
+
+    /// Pages added to an existing CBT archive, after the ones it has
+    pub fn append<P: AsRef<Path>>(self, path: P) -> CbtResult<CbtSummary> {
+        if self.comic_info.is_some() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Metadata can not be generated when appending",
+            )
+            .into());
+        }
+        let path = path.as_ref();
+        let (_, summary) = self.build(true, |pages| {
+            CbtWriter::append(path, pages.iter().map(|page| page.chapter.as_str()))
+        })?;
+        Ok(summary)
+    }
+
+    // Pages are numbered within each chapter
+    fn padding(pages: &[InputPage]) -> usize {
+        let mut chapter_sizes: HashMap<&str, usize> = HashMap::new();
+        for page in pages {
+            *chapter_sizes.entry(&page.chapter).or_default() += 1;
+        }
+        chapter_sizes.values().max().unwrap().ilog10() as usize + 1
+    }
+
+    fn build(
+        self,
+        append: bool,
+        open: impl FnOnce(&[InputPage]) -> std::io::Result<CbtWriter>,
+    ) -> CbtResult<(Box<dyn Write>, CbtSummary)> {
+        if let Some(nice) = self.nice {
+            set_nice(nice)?;
+        }
+        if self.idle_io {
+            set_idle_io()?;
+        }
+        let inputs = Inputs::collect(self.paths, self.options);
+        check_signal()?;
+        let mut inputs = inputs?;
+        if inputs.pages.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "No pages to archive").into());
+        }
+
+        let state = self
+            .state_dir
+            .map(|(dir, resume)| StateDir::open(dir, resume))
+            .transpose()?;
+        let mut cbt = open(&inputs.pages)?;
+        let settings = match self.settings {
+            Some(settings) => settings,
+            None => EncoderSettings::preset("archive")?,
+        };
+        cbt.set_encoder(self.encoder, settings);
+        cbt.set_failure_policy(self.on_error);
+        if let Some(jobs) = self.jobs {
+            cbt.set_jobs(jobs);
+        }
+        if let Some(threads) = self.threads_per_job {
+            cbt.set_threads_per_job(threads);
+        }
+        if let Some(min_savings) = self.min_savings {
+            cbt.set_keep_smaller(min_savings);
+        }
+        if let Some(cache) = self.cache {
+            cbt.set_cache(cache);
+        }
+        if let Some(state) = state {
+            cbt.set_state_dir(state);
+        }
+        if let Some(handler) = self.events {
+            cbt.set_event_handler(handler);
+        }
+        if let Some(bar) = self.progress {
+            cbt.set_progress(inputs.pages.len(), bar);
+        }
+        if let Some(max_ahead) = self.max_ahead {
+            cbt.set_max_ahead(max_ahead);
+        }
+        for warning in &inputs.warnings {
+            cbt.warn(warning);
+        }
+        match (self.comic_info, inputs.comic_info.take()) {
+            (Some(comic_info), file) => {
+                if let Some(file) = file {
+                    cbt.warn(&format!(
+                        "Replacing '{}' with generated metadata",
+                        file.display()
+                    ));
+                }
+                cbt.set_comic_info(comic_info);
+            }
+            (None, Some(file)) if append => {
+                cbt.warn(&format!("Ignoring '{}' when appending", file.display()))
+            }
+            (None, Some(file)) => cbt.set_comic_info_file(file),
+            (None, None) => {}
+        }
+
+        for page in &inputs.pages {
+            cbt.set_chapter(&page.chapter);
+            cbt.submit(&page.path, page.format)?;
+        }
+        let (writer, summary) = cbt.finish()?;
+        inputs.close()?;
+        Ok((writer, summary))
+    }
+}
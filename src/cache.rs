@@ -0,0 +1,354 @@
+//
+// Copyright 2024-2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+use std::fs::File;
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use std::{env, fs};
+
+use crate::image::ImageFormat;
+
+// SHA-256 (FIPS 180-4) for cache keys
+pub(crate) struct Sha256 {
+    state: [u32; 8],
+    block: [u8; 64],
+    block_len: usize,
+    len: u64,
+}
+
+impl Sha256 {
+    const K: [u32; 64] = [
+        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4,
+        0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe,
+        0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f,
+        0x4a7484aa, 0x5cb0a9dc, 0x76f988da, 0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7,
+        0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc,
+        0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
+        0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070, 0x19a4c116,
+        0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+        0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7,
+        0xc67178f2,
+    ];
+
+    pub(crate) fn new() -> Self {
+        Self {
+            state: [
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+                0x5be0cd19,
+            ],
+            block: [0; 64],
+            block_len: 0,
+            len: 0,
+        }
+    }
+
+    pub(crate) fn update(&mut self, mut data: &[u8]) {
+        self.len += data.len() as u64;
+        while !data.is_empty() {
+            let count = data.len().min(64 - self.block_len);
+            self.block[self.block_len..self.block_len + count].copy_from_slice(&data[..count]);
+            self.block_len += count;
+            data = &data[count..];
+            if self.block_len == 64 {
+                self.compress();
+                self.block_len = 0;
+            }
+        }
+    }
+
+    fn compress(&mut self) {
+        let mut w = [0u32; 64];
+        for (i, word) in self.block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for (k, w) in Self::K.iter().zip(w) {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(*k)
+                .wrapping_add(w);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            (h, g, f, e, d, c, b, a) = (g, f, e, d.wrapping_add(t1), c, b, a, t1.wrapping_add(t2));
+        }
+        for (state, value) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+
+    // Digest as lowercase hex
+    pub(crate) fn hex(mut self) -> String {
+        let bits = self.len * 8;
+        self.update(&[0x80]);
+        while self.block_len != 56 {
+            self.update(&[0]);
+        }
+        self.update(&bits.to_be_bytes());
+        self.state
+            .iter()
+            .map(|word| format!("{word:08x}"))
+            .collect()
+    }
+
+    pub(crate) fn file(path: &Path) -> Result<String> {
+        let mut hash = Self::new();
+        std::io::copy(&mut File::open(path)?, &mut hash)?;
+        Ok(hash.hex())
+    }
+}
+
+impl Write for Sha256 {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Encoded pages kept between runs, in files named after a hash of the source
+/// together with the encoder, its version and its arguments, and a hash of the
+/// page itself
+#[derive(Debug)]
+pub struct CacheEntry {
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+#[derive(Debug)]
+pub struct EncodeCache {
+    dir: PathBuf,
+}
+
+impl EncodeCache {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    /// $XDG_CACHE_HOME/mkcbt or ~/.cache/mkcbt
+    pub fn default_dir() -> Result<PathBuf> {
+        env::var_os("XDG_CACHE_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+            .map(|dir| dir.join("mkcbt"))
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::NotFound,
+                    "No default cache directory, use '--cache-dir'",
+                )
+            })
+    }
+
+    // Entries are touched when used, so pruning drops the least recently used,
+    // and only reused while their contents match their name
+    pub(crate) fn lookup(&self, key: &str, format: ImageFormat) -> Option<PathBuf> {
+        let prefix = format!("{key}.");
+        let suffix = format!(".{}", format.extension());
+        for entry in fs::read_dir(self.dir.join(&key[..2])).ok()?.flatten() {
+            let name = entry.file_name();
+            let Some(hash) = name
+                .to_str()
+                .and_then(|x| x.strip_prefix(&prefix)?.strip_suffix(&suffix))
+            else {
+                continue;
+            };
+            let path = entry.path();
+            if Sha256::file(&path).is_ok_and(|x| x == hash) {
+                if let Ok(file) = File::open(&path) {
+                    let _ = file.set_modified(SystemTime::now());
+                }
+                return Some(path);
+            }
+            let _ = fs::remove_file(&path);
+        }
+        None
+    }
+
+    pub(crate) fn store(&self, key: &str, format: ImageFormat, file: &Path) -> Result<()> {
+        let path = self.dir.join(&key[..2]).join(format!(
+            "{key}.{}.{}",
+            Sha256::file(file)?,
+            format.extension()
+        ));
+        let temp = path.with_extension(format!("{}.tmp", std::process::id()));
+        fs::create_dir_all(self.dir.join(&key[..2]))?;
+        fs::copy(file, &temp)?;
+        fs::rename(&temp, &path).inspect_err(|_| {
+            let _ = fs::remove_file(&temp);
+        })
+    }
+
+    /// Oldest first
+    pub fn entries(&self) -> Result<Vec<CacheEntry>> {
+        let mut entries = Vec::new();
+        let dirs = match fs::read_dir(&self.dir) {
+            Ok(dirs) => dirs,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(entries),
+            Err(err) => return Err(err),
+        };
+        for dir in dirs {
+            let dir = dir?;
+            if !dir.file_type()?.is_dir() {
+                continue;
+            }
+            for file in fs::read_dir(dir.path())? {
+                let file = file?;
+                let metadata = file.metadata()?;
+                if metadata.is_file() {
+                    entries.push(CacheEntry {
+                        path: file.path(),
+                        size: metadata.len(),
+                        modified: metadata.modified()?,
+                    });
+                }
+            }
+        }
+        entries.sort_by_key(|entry| entry.modified);
+        Ok(entries)
+    }
+
+    pub fn remove(&self, entry: &CacheEntry) -> Result<()> {
+        fs::remove_file(&entry.path)?;
+        if let Some(dir) = entry.path.parent() {
+            // Only succeeds once the directory is empty
+            let _ = fs::remove_dir(dir);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::files::TempDir;
+
+    fn sha256(data: &[u8]) -> String {
+        let mut sha = Sha256::new();
+        sha.update(data);
+        sha.hex()
+    }
+
+    #[test]
+    fn sha256_vectors() {
+        assert_eq!(
+            sha256(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            sha256(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        // Padding spills into a second block
+        assert_eq!(
+            sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        assert_eq!(
+            sha256(&[b'a'; 1_000_000]),
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+        );
+    }
+
+    #[test]
+    fn sha256_split_updates() {
+        let data: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        let mut sha = Sha256::new();
+        for chunk in data.chunks(63) {
+            sha.write_all(chunk).unwrap();
+        }
+        assert_eq!(sha.hex(), sha256(&data));
+    }
+
+    #[test]
+    fn store_lookup_prune() {
+        let temp = TempDir::new("mkcbt-test").unwrap();
+        let cache = EncodeCache::new(temp.path().join("cache"));
+        let (a, b) = (sha256(b"a"), sha256(b"b"));
+        let page = temp.path().join("page.avif");
+        assert_eq!(cache.lookup(&a, ImageFormat::Avif), None);
+        for (key, data) in [(&a, b"first"), (&b, b"other")] {
+            fs::write(&page, data).unwrap();
+            cache.store(key, ImageFormat::Avif, &page).unwrap();
+        }
+        assert_eq!(cache.lookup(&a, ImageFormat::Jxl), None);
+        let path = cache.lookup(&a, ImageFormat::Avif).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"first");
+
+        // Used entries become the most recent
+        let old = SystemTime::now() - Duration::from_secs(86400);
+        for entry in cache.entries().unwrap() {
+            File::open(&entry.path).unwrap().set_modified(old).unwrap();
+        }
+        File::open(&path)
+            .unwrap()
+            .set_modified(old - Duration::from_secs(1))
+            .unwrap();
+        cache.lookup(&a, ImageFormat::Avif).unwrap();
+        let entries = cache.entries().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].path, path);
+        assert_eq!(entries[1].size, 5);
+
+        cache.remove(&entries[1]).unwrap();
+        assert_eq!(cache.lookup(&a, ImageFormat::Avif), None);
+        assert!(!path.parent().unwrap().exists());
+        assert_eq!(cache.entries().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn damaged_entry_dropped() {
+        let temp = TempDir::new("mkcbt-test").unwrap();
+        let cache = EncodeCache::new(temp.path().join("cache"));
+        let key = sha256(b"a");
+        let page = temp.path().join("page.avif");
+        fs::write(&page, b"complete page").unwrap();
+        cache.store(&key, ImageFormat::Avif, &page).unwrap();
+        let path = cache.lookup(&key, ImageFormat::Avif).unwrap();
+
+        fs::write(&path, b"complete").unwrap();
+        assert_eq!(cache.lookup(&key, ImageFormat::Avif), None);
+        assert!(!path.exists());
+        assert!(cache.entries().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,358 @@
+//
+// Copyright 2024-2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+// ComicInfo.xml metadata
+pub(crate) struct PageInfo {
+    pub(crate) size: u64,
+    pub(crate) dimensions: Option<(u32, u32)>,
+    pub(crate) bookmark: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct ComicInfo {
+    values: Vec<Option<String>>,
+    pub bookmarks: bool,
+}
+
+impl Default for ComicInfo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ComicInfo {
+    // Element order of the ComicInfo schema
+    const FIELDS: [&str; 44] = [
+        "Title",
+        "Series",
+        "Number",
+        "Count",
+        "Volume",
+        "AlternateSeries",
+        "AlternateNumber",
+        "AlternateCount",
+        "Summary",
+        "Notes",
+        "Year",
+        "Month",
+        "Day",
+        "Writer",
+        "Penciller",
+        "Inker",
+        "Colorist",
+        "Letterer",
+        "CoverArtist",
+        "Editor",
+        "Translator",
+        "Publisher",
+        "Imprint",
+        "Genre",
+        "Tags",
+        "Web",
+        "PageCount",
+        "LanguageISO",
+        "Format",
+        "BlackAndWhite",
+        "Manga",
+        "Characters",
+        "Teams",
+        "Locations",
+        "ScanInformation",
+        "StoryArc",
+        "StoryArcNumber",
+        "SeriesGroup",
+        "AgeRating",
+        "Pages",
+        "CommunityRating",
+        "MainCharacterOrTeam",
+        "Review",
+        "GTIN",
+    ];
+
+    // Command line options for the common fields
+    const OPTIONS: [(&str, &str); 24] = [
+        ("--title", "Title"),
+        ("--series", "Series"),
+        ("--number", "Number"),
+        ("--count", "Count"),
+        ("--volume", "Volume"),
+        ("--summary", "Summary"),
+        ("--year", "Year"),
+        ("--month", "Month"),
+        ("--day", "Day"),
+        ("--writer", "Writer"),
+        ("--penciller", "Penciller"),
+        ("--inker", "Inker"),
+        ("--colorist", "Colorist"),
+        ("--letterer", "Letterer"),
+        ("--cover-artist", "CoverArtist"),
+        ("--editor", "Editor"),
+        ("--translator", "Translator"),
+        ("--publisher", "Publisher"),
+        ("--genre", "Genre"),
+        ("--tags", "Tags"),
+        ("--web", "Web"),
+        ("--language", "LanguageISO"),
+        ("--manga", "Manga"),
+        ("--age-rating", "AgeRating"),
+    ];
+
+    pub fn new() -> Self {
+        Self {
+            values: vec![None; Self::FIELDS.len()],
+            bookmarks: false,
+        }
+    }
+
+    pub fn option_field(option: &str) -> Option<&'static str> {
+        Self::OPTIONS
+            .iter()
+            .find(|(name, _)| *name == option)
+            .map(|&(_, field)| field)
+    }
+
+    pub fn set(&mut self, field: &str, value: &str) -> Result<()> {
+        let invalid = |message: String| Error::new(ErrorKind::InvalidInput, message);
+        let index = Self::FIELDS
+            .iter()
+            .position(|known| known.eq_ignore_ascii_case(field))
+            .filter(|&i| !matches!(Self::FIELDS[i], "Pages" | "PageCount"))
+            .ok_or_else(|| invalid(format!("Unknown ComicInfo field '{field}'")))?;
+        let value = value.trim();
+        let value = match Self::FIELDS[index] {
+            // Issue numbers may have a fraction, as in 12.5
+            "Number" => {
+                value
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|x| x.is_finite())
+                    .ok_or_else(|| invalid("Number must be a number".to_string()))?;
+                value.to_string()
+            }
+            "Count" | "Volume" | "Year" | "Month" | "Day" => {
+                value
+                    .parse::<i32>()
+                    .map_err(|_| invalid(format!("{} must be a number", Self::FIELDS[index])))?;
+                value.to_string()
+            }
+            "Manga" => match value.to_ascii_lowercase().as_str() {
+                "yes" => "Yes".to_string(),
+                "no" => "No".to_string(),
+                "rtl" | "yesandrighttoleft" => "YesAndRightToLeft".to_string(),
+                "unknown" => "Unknown".to_string(),
+                _ => return Err(invalid(format!("Invalid Manga value '{value}'"))),
+            },
+            _ => value.to_string(),
+        };
+        self.values[index] = Some(value);
+        Ok(())
+    }
+
+    /// Sidecar files hold one Field=Value per line
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        for line in fs::read_to_string(&path)?.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (field, value) = line.split_once('=').ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Bad line in '{}': {line}", path.as_ref().display()),
+                )
+            })?;
+            self.set(field.trim(), value)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn to_xml(&self, pages: &[PageInfo]) -> String {
+        let mut xml = String::from(concat!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n",
+            "<ComicInfo xmlns:xsd=\"http://www.w3.org/2001/XMLSchema\" ",
+            "xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\">\n"
+        ));
+        for (field, value) in Self::FIELDS.iter().zip(&self.values) {
+            match *field {
+                "PageCount" => xml += &format!("  <PageCount>{}</PageCount>\n", pages.len()),
+                "Pages" => {
+                    xml += "  <Pages>\n";
+                    for (i, page) in pages.iter().enumerate() {
+                        xml += &format!("    <Page Image=\"{i}\"");
+                        if i == 0 {
+                            xml += " Type=\"FrontCover\"";
+                        }
+                        xml += &format!(" ImageSize=\"{}\"", page.size);
+                        if let Some((width, height)) = page.dimensions {
+                            xml += &format!(" ImageWidth=\"{width}\" ImageHeight=\"{height}\"");
+                        }
+                        if let Some(bookmark) = page.bookmark.as_ref().filter(|_| self.bookmarks) {
+                            xml += &format!(" Bookmark=\"{}\"", Self::escape(bookmark));
+                        }
+                        xml += " />\n";
+                    }
+                    xml += "  </Pages>\n";
+                }
+                _ => {
+                    if let Some(value) = value {
+                        xml += &format!("  <{field}>{}</{field}>\n", Self::escape(value));
+                    }
+                }
+            }
+        }
+        xml += "</ComicInfo>\n";
+        xml
+    }
+
+    fn escape(text: &str) -> String {
+        let mut escaped = String::with_capacity(text.len());
+        for c in text.chars() {
+            match c {
+                '&' => escaped.push_str("&amp;"),
+                '<' => escaped.push_str("&lt;"),
+                '>' => escaped.push_str("&gt;"),
+                '"' => escaped.push_str("&quot;"),
+                '\'' => escaped.push_str("&apos;"),
+                _ => escaped.push(c),
+            }
+        }
+        escaped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_validates() {
+        let mut info = ComicInfo::new();
+        for (field, value) in [("Number", "one"), ("Number", "inf"), ("Year", "1999a")] {
+            let error = info.set(field, value).unwrap_err();
+            assert_eq!(error.to_string(), format!("{field} must be a number"));
+        }
+        assert!(info.set("Manga", "maybe").is_err());
+        assert!(info.set("PageCount", "3").is_err());
+        assert!(info.set("Pages", "").is_err());
+        assert!(info.set("Colour", "red").is_err());
+
+        info.set("number", " 12.5 ").unwrap();
+        info.set("YEAR", "1999").unwrap();
+        for (value, stored) in [
+            ("yes", "Yes"),
+            ("No", "No"),
+            ("RTL", "YesAndRightToLeft"),
+            ("YesAndRightToLeft", "YesAndRightToLeft"),
+            ("unknown", "Unknown"),
+        ] {
+            info.set("Manga", value).unwrap();
+            assert!(
+                info.to_xml(&[])
+                    .contains(&format!("<Manga>{stored}</Manga>"))
+            );
+        }
+        let xml = info.to_xml(&[]);
+        assert!(xml.contains("<Number>12.5</Number>"));
+        assert!(xml.contains("<Year>1999</Year>"));
+    }
+
+    #[test]
+    fn escape_markup() {
+        assert_eq!(
+            ComicInfo::escape("Tom & Jerry's <\"Best\">"),
+            "Tom &amp; Jerry&apos;s &lt;&quot;Best&quot;&gt;"
+        );
+        assert_eq!(ComicInfo::escape("plain ü"), "plain ü");
+    }
+
+    #[test]
+    fn xml_in_schema_order() {
+        let mut info = ComicInfo::new();
+        for (field, value) in [
+            ("Manga", "yes"),
+            ("Title", "T"),
+            ("Writer", "W"),
+            ("Year", "2001"),
+        ] {
+            info.set(field, value).unwrap();
+        }
+        let xml = info.to_xml(&[]);
+        let elements: Vec<_> = xml
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix('<')?.split(['>', ' ', '/']).next())
+            .filter(|name| !name.is_empty() && !name.starts_with(['?', '/']))
+            .collect();
+        assert_eq!(
+            elements,
+            [
+                "ComicInfo",
+                "Title",
+                "Year",
+                "Writer",
+                "PageCount",
+                "Manga",
+                "Pages"
+            ]
+        );
+    }
+
+    #[test]
+    fn page_list() {
+        let pages = [
+            PageInfo {
+                size: 100,
+                dimensions: Some((800, 1200)),
+                bookmark: Some("Part <1>".to_string()),
+            },
+            PageInfo {
+                size: 200,
+                dimensions: None,
+                bookmark: None,
+            },
+            PageInfo {
+                size: 300,
+                dimensions: Some((10, 20)),
+                bookmark: Some("Part 2".to_string()),
+            },
+        ];
+        let mut info = ComicInfo::new();
+        info.bookmarks = true;
+        let xml = info.to_xml(&pages);
+        assert!(xml.contains(concat!(
+            "  <PageCount>3</PageCount>\n",
+            "  <Pages>\n",
+            "    <Page Image=\"0\" Type=\"FrontCover\" ImageSize=\"100\" ",
+            "ImageWidth=\"800\" ImageHeight=\"1200\" Bookmark=\"Part &lt;1&gt;\" />\n",
+            "    <Page Image=\"1\" ImageSize=\"200\" />\n",
+            "    <Page Image=\"2\" ImageSize=\"300\" ImageWidth=\"10\" ImageHeight=\"20\" ",
+            "Bookmark=\"Part 2\" />\n",
+            "  </Pages>\n",
+        )));
+
+        info.bookmarks = false;
+        assert!(!info.to_xml(&pages).contains("Bookmark"));
+    }
+}
@@ -0,0 +1,382 @@
+//
+// Copyright 2024-2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+use std::env;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::UNIX_EPOCH;
+
+use crate::image::ImageFormat;
+
+/// Encoder backends
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Encoder {
+    #[default]
+    Avif,
+    Jxl,
+    Webp,
+    Passthrough,
+}
+
+impl Encoder {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "avif" => Ok(Self::Avif),
+            "jxl" => Ok(Self::Jxl),
+            "webp" => Ok(Self::Webp),
+            "copy" | "passthrough" => Ok(Self::Passthrough),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unknown encoder '{name}' (expected one of: avif, jxl, webp, copy)"),
+            )),
+        }
+    }
+
+    pub fn program(&self) -> &'static str {
+        match self {
+            Self::Avif => "avifenc",
+            Self::Jxl => "cjxl",
+            Self::Webp => "cwebp",
+            Self::Passthrough => "",
+        }
+    }
+
+    /// Format of encoded pages, or None to keep the original
+    pub fn target(&self) -> Option<ImageFormat> {
+        match self {
+            Self::Avif => Some(ImageFormat::Avif),
+            Self::Jxl => Some(ImageFormat::Jxl),
+            Self::Webp => Some(ImageFormat::Webp),
+            Self::Passthrough => None,
+        }
+    }
+
+    /// Input formats the encoder can read, others are stored as they are
+    pub fn reads(&self, format: ImageFormat) -> bool {
+        match self {
+            Self::Avif => matches!(format, ImageFormat::Jpeg | ImageFormat::Png),
+            Self::Jxl => matches!(
+                format,
+                ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::Gif
+            ),
+            Self::Webp => matches!(
+                format,
+                ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::Tiff | ImageFormat::Webp
+            ),
+            Self::Passthrough => true,
+        }
+    }
+
+    pub(crate) fn command(
+        &self,
+        settings: &EncoderSettings,
+        threads: usize,
+        input: &Path,
+        input_format: ImageFormat,
+        output: &Path,
+    ) -> Command {
+        let mut command = Command::new(self.program());
+        match self {
+            Self::Avif => {
+                command
+                    .args(["--jobs".to_string(), threads.to_string()])
+                    .args(self.args(settings, input_format))
+                    .arg(input)
+                    .arg(output);
+            }
+            Self::Jxl => {
+                command
+                    .args(["--num_threads".to_string(), threads.to_string()])
+                    .args(self.args(settings, input_format))
+                    .arg(input)
+                    .arg(output);
+            }
+            Self::Webp => {
+                if threads > 1 {
+                    command.arg("-mt");
+                }
+                command
+                    .args(self.args(settings, input_format))
+                    .arg(input)
+                    .arg("-o")
+                    .arg(output);
+            }
+            Self::Passthrough => unreachable!("passthrough pages are never encoded"),
+        }
+        command
+    }
+
+    // Arguments that affect the encoded page, leaving out threads and paths
+    pub(crate) fn args(
+        &self,
+        settings: &EncoderSettings,
+        input_format: ImageFormat,
+    ) -> Vec<String> {
+        match self {
+            Self::Avif => settings.avif_args(),
+            Self::Jxl => settings.jxl_args(input_format == ImageFormat::Jpeg),
+            Self::Webp => settings.webp_args(),
+            Self::Passthrough => Vec::new(),
+        }
+    }
+
+    // Version output of the encoder, or failing that the path and modification
+    // time of the program, or None if it could not be found
+    pub(crate) fn version(&self) -> Option<String> {
+        let flag = match self {
+            Self::Avif | Self::Jxl => "--version",
+            Self::Webp => "-version",
+            Self::Passthrough => return None,
+        };
+        let output = Command::new(self.program())
+            .arg(flag)
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output();
+        match output {
+            Ok(output) if output.status.success() => {
+                Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+            }
+            _ => self.installed(),
+        }
+    }
+
+    fn installed(&self) -> Option<String> {
+        env::split_paths(&env::var_os("PATH")?).find_map(|dir| {
+            let path = dir.join(self.program());
+            let metadata = path.metadata().ok().filter(|x| x.is_file())?;
+            let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+            Some(format!("{} {}", path.display(), modified.as_nanos()))
+        })
+    }
+}
+
+/// Encoder settings, with quantizers, subsampling and depth used by avifenc only
+#[derive(Clone, Debug, Default)]
+pub struct EncoderSettings {
+    pub quality: Option<u8>,
+    pub min_quantizer: Option<u8>,
+    pub max_quantizer: Option<u8>,
+    pub speed: Option<u8>,
+    pub yuv: Option<u16>,
+    pub depth: Option<u8>,
+    pub lossless: bool,
+    pub lossless_jpeg: bool,
+}
+
+impl EncoderSettings {
+    const PRESETS: [&str; 3] = ["archive", "balanced", "fast"];
+
+    pub fn preset(name: &str) -> Result<Self> {
+        match name {
+            // Smallest output, slowest encode
+            "archive" => Ok(Self {
+                speed: Some(0),
+                ..Default::default()
+            }),
+            "balanced" => Ok(Self {
+                quality: Some(65),
+                speed: Some(6),
+                ..Default::default()
+            }),
+            "fast" => Ok(Self {
+                quality: Some(60),
+                speed: Some(9),
+                ..Default::default()
+            }),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Unknown preset '{name}' (expected one of: {})",
+                    Self::PRESETS.join(", ")
+                ),
+            )),
+        }
+    }
+
+    /// Fill in settings not given explicitly
+    pub fn or(self, base: Self) -> Self {
+        Self {
+            quality: self.quality.or(base.quality),
+            min_quantizer: self.min_quantizer.or(base.min_quantizer),
+            max_quantizer: self.max_quantizer.or(base.max_quantizer),
+            speed: self.speed.or(base.speed),
+            yuv: self.yuv.or(base.yuv),
+            depth: self.depth.or(base.depth),
+            lossless: self.lossless || base.lossless,
+            lossless_jpeg: self.lossless_jpeg || base.lossless_jpeg,
+        }
+    }
+
+    fn avif_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(speed) = self.speed {
+            args.extend(["--speed".to_string(), speed.to_string()]);
+        }
+        if let Some(depth) = self.depth {
+            args.extend(["--depth".to_string(), depth.to_string()]);
+        }
+        if self.lossless {
+            // Quality and subsampling are implied by lossless mode
+            args.push("--lossless".to_string());
+            return args;
+        }
+        if let Some(quality) = self.quality {
+            args.extend(["-q".to_string(), quality.to_string()]);
+        }
+        if let Some(min_quantizer) = self.min_quantizer {
+            args.extend(["--min".to_string(), min_quantizer.to_string()]);
+        }
+        if let Some(max_quantizer) = self.max_quantizer {
+            args.extend(["--max".to_string(), max_quantizer.to_string()]);
+        }
+        if let Some(yuv) = self.yuv {
+            args.extend(["--yuv".to_string(), yuv.to_string()]);
+        }
+        args
+    }
+
+    // Speed 0-10 maps onto effort 9-1
+    fn jxl_args(&self, jpeg_input: bool) -> Vec<String> {
+        let lossless_jpeg = self.lossless_jpeg && jpeg_input;
+        let mut args = vec![format!("--lossless_jpeg={}", lossless_jpeg as u8)];
+        if let Some(speed) = self.speed {
+            args.extend(["--effort".to_string(), (9 - speed * 8 / 10).to_string()]);
+        }
+        if lossless_jpeg {
+            // cjxl refuses a distance or quality when transcoding JPEG
+            return args;
+        }
+        if self.lossless {
+            args.extend(["--distance".to_string(), "0".to_string()]);
+        } else if let Some(quality) = self.quality {
+            args.extend(["--quality".to_string(), quality.to_string()]);
+        }
+        args
+    }
+
+    // Speed 0-10 maps onto method 6-0
+    fn webp_args(&self) -> Vec<String> {
+        let mut args = vec!["-quiet".to_string()];
+        if let Some(speed) = self.speed {
+            args.extend(["-m".to_string(), (6 - speed * 6 / 10).to_string()]);
+        }
+        if self.lossless {
+            args.push("-lossless".to_string());
+        } else if let Some(quality) = self.quality {
+            args.extend(["-q".to_string(), quality.to_string()]);
+        }
+        args
+    }
+}
+
+// Lowered priority for mkcbt and the encoders it starts, which inherit it
+#[cfg(unix)]
+pub(crate) fn set_nice(nice: i32) -> Result<()> {
+    const PRIO_PROCESS: i32 = 0;
+    unsafe extern "C" {
+        fn setpriority(which: i32, who: u32, prio: i32) -> i32;
+    }
+    match unsafe { setpriority(PRIO_PROCESS, 0, nice) } {
+        0 => Ok(()),
+        _ => Err(Error::last_os_error()),
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn set_nice(_nice: i32) -> Result<()> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "Option '--nice' is not supported on this platform",
+    ))
+}
+
+// Disk access only when nothing else wants it
+#[cfg(all(
+    target_os = "linux",
+    any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    )
+))]
+pub(crate) fn set_idle_io() -> Result<()> {
+    #[cfg(target_arch = "x86_64")]
+    const SYS_IOPRIO_SET: i64 = 251;
+    #[cfg(not(target_arch = "x86_64"))]
+    const SYS_IOPRIO_SET: i64 = 30;
+    const IOPRIO_WHO_PROCESS: i64 = 1;
+    const IOPRIO_CLASS_IDLE: i64 = 3 << 13;
+    unsafe extern "C" {
+        fn syscall(number: i64, ...) -> i64;
+    }
+    match unsafe { syscall(SYS_IOPRIO_SET, IOPRIO_WHO_PROCESS, 0i64, IOPRIO_CLASS_IDLE) } {
+        0 => Ok(()),
+        _ => Err(Error::last_os_error()),
+    }
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    )
+)))]
+pub(crate) fn set_idle_io() -> Result<()> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "Option '--idle-io' is not supported on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn readable_inputs() {
+        let formats = [
+            ImageFormat::Jpeg,
+            ImageFormat::Png,
+            ImageFormat::Webp,
+            ImageFormat::Gif,
+            ImageFormat::Bmp,
+            ImageFormat::Tiff,
+            ImageFormat::Avif,
+            ImageFormat::Jxl,
+        ];
+        let readable = |encoder: Encoder| -> Vec<&str> {
+            formats
+                .iter()
+                .filter(|&&format| encoder.reads(format))
+                .map(|format| format.extension())
+                .collect()
+        };
+        assert_eq!(readable(Encoder::Avif), ["jpg", "png"]);
+        assert_eq!(readable(Encoder::Jxl), ["jpg", "png", "gif"]);
+        assert_eq!(readable(Encoder::Webp), ["jpg", "png", "webp", "tif"]);
+        assert_eq!(readable(Encoder::Passthrough).len(), formats.len());
+    }
+}
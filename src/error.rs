@@ -0,0 +1,120 @@
+//
+// Copyright 2024-2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+use std::process::ExitStatus;
+
+/// Archive creation errors
+#[derive(Debug)]
+pub enum CbtError {
+    Io(Error),
+    Spawn {
+        program: &'static str,
+        page: usize,
+        input: PathBuf,
+        source: Error,
+    },
+    Encoder {
+        program: &'static str,
+        page: usize,
+        input: PathBuf,
+        status: ExitStatus,
+        stderr: String,
+    },
+    // Stopped by SIGINT or SIGTERM
+    Interrupted(i32),
+    // Archive completed, but without some pages converted
+    Partial {
+        failed: usize,
+        total: usize,
+    },
+}
+
+pub type CbtResult<T> = std::result::Result<T, CbtError>;
+
+impl fmt::Display for CbtError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(err) => err.fmt(f),
+            Self::Spawn {
+                program,
+                page,
+                input,
+                source,
+            } => write!(
+                f,
+                "Failed to run {program} for page {page} ('{}'): {source}",
+                input.display()
+            ),
+            Self::Encoder {
+                program,
+                page,
+                input,
+                status,
+                stderr,
+            } => {
+                write!(
+                    f,
+                    "{program} failed on page {page} ('{}') with {status}",
+                    input.display()
+                )?;
+                for line in stderr.lines() {
+                    write!(f, "\n  {line}")?;
+                }
+                Ok(())
+            }
+            Self::Interrupted(_) => write!(f, "Interrupted"),
+            Self::Partial { failed, total } => {
+                write!(f, "{failed} of {total} pages could not be converted")
+            }
+        }
+    }
+}
+
+impl CbtError {
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Self::Interrupted(signal) => 128 + *signal as u8,
+            // As if killed by SIGPIPE
+            Self::Io(err) if err.kind() == ErrorKind::BrokenPipe => 141,
+            Self::Partial { .. } => 2,
+            _ => 1,
+        }
+    }
+}
+
+impl std::error::Error for CbtError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(err) | Self::Spawn { source: err, .. } => Some(err),
+            Self::Encoder { .. } | Self::Interrupted(_) | Self::Partial { .. } => None,
+        }
+    }
+}
+
+impl From<Error> for CbtError {
+    fn from(err: Error) -> Self {
+        Self::Io(err)
+    }
+}
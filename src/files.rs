@@ -0,0 +1,311 @@
+//
+// Copyright 2024-2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+use std::fs::File;
+use std::io::{Cursor, Error, ErrorKind, Read, Result, Seek, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{env, fs};
+
+// Temporary directories
+pub(crate) struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub(crate) fn new(prefix: &str) -> Result<Self> {
+        let mut time_val = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .subsec_nanos();
+        let mut path = env::temp_dir().join(format!("{prefix}-{:08x}", time_val));
+        while path.exists() {
+            time_val = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .subsec_nanos();
+            path = env::temp_dir().join(format!("{prefix}-{:08x}", time_val));
+        }
+        fs::create_dir(&path)?;
+        Ok(Self { path })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        self.path.as_path()
+    }
+
+    // Removed only once, closing again does nothing
+    pub(crate) fn close(&mut self) -> Result<()> {
+        if self.path.as_os_str().is_empty() {
+            return Ok(());
+        }
+        fs::remove_dir_all(std::mem::take(&mut self.path))
+    }
+}
+
+// Best effort when not closed
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+// Output written beside its target and renamed over it once complete
+pub(crate) struct AtomicFile {
+    file: File,
+    temp: PathBuf,
+    target: PathBuf,
+    committed: bool,
+}
+
+impl AtomicFile {
+    pub(crate) fn create<P: AsRef<Path>>(target: P) -> Result<Self> {
+        let target = target.as_ref().to_path_buf();
+        let name = target.file_name().ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("'{}' is not a file name", target.display()),
+            )
+        })?;
+        let temp = target.with_file_name(format!(
+            ".{}.{}.tmp",
+            name.to_string_lossy(),
+            std::process::id()
+        ));
+        Ok(Self {
+            file: File::create_new(&temp)?,
+            temp,
+            target,
+            committed: false,
+        })
+    }
+
+    pub(crate) fn writer(&self) -> Result<File> {
+        self.file.try_clone()
+    }
+
+    pub(crate) fn commit(mut self) -> Result<()> {
+        self.file.sync_all()?;
+        fs::rename(&self.temp, &self.target)?;
+        self.committed = true;
+
+        // Make the rename itself durable where directories can be synced
+        if let Some(parent) = self.target.parent()
+            && let Ok(dir) = File::open(if parent.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                parent
+            })
+        {
+            let _ = dir.sync_all();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.temp);
+        }
+    }
+}
+
+// Data of unknown length, held in memory up to a limit and in a temporary file
+// beyond it, for entries whose size must be known before they are written
+pub(crate) enum Spool {
+    Memory(Cursor<Vec<u8>>),
+    File(File, TempDir),
+}
+
+impl Spool {
+    const MEMORY_LIMIT: u64 = 16 << 20;
+
+    pub(crate) fn new(mut reader: impl Read) -> Result<Self> {
+        let mut data = Vec::new();
+        (&mut reader)
+            .take(Self::MEMORY_LIMIT + 1)
+            .read_to_end(&mut data)?;
+        if data.len() as u64 <= Self::MEMORY_LIMIT {
+            return Ok(Self::Memory(Cursor::new(data)));
+        }
+
+        let dir = TempDir::new("mkcbt-spool")?;
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(dir.path().join("data"))?;
+        file.write_all(&data)?;
+        std::io::copy(&mut reader, &mut file)?;
+        file.rewind()?;
+        Ok(Self::File(file, dir))
+    }
+
+    pub(crate) fn len(&self) -> Result<u64> {
+        match self {
+            Self::Memory(data) => Ok(data.get_ref().len() as u64),
+            Self::File(file, _) => Ok(file.metadata()?.len()),
+        }
+    }
+
+    pub(crate) fn rewind(&mut self) -> Result<()> {
+        match self {
+            Self::Memory(data) => data.set_position(0),
+            Self::File(file, _) => file.rewind()?,
+        }
+        Ok(())
+    }
+
+    pub(crate) fn close(self) -> Result<()> {
+        match self {
+            Self::Memory(_) => Ok(()),
+            Self::File(file, mut dir) => {
+                drop(file);
+                dir.close()
+            }
+        }
+    }
+}
+
+impl Read for Spool {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self {
+            Self::Memory(data) => data.read(buf),
+            Self::File(file, _) => file.read(buf),
+        }
+    }
+}
+
+// Only allow relative paths that stay inside the destination
+pub(crate) fn safe_entry_path(name: &str) -> Result<PathBuf> {
+    let path = Path::new(name);
+    if path
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        Ok(path.to_path_buf())
+    } else {
+        Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Refusing to extract unsafe path '{name}'"),
+        ))
+    }
+}
+
+pub(crate) fn create_entry_file(directory: &Path, name: &str) -> Result<File> {
+    let path = directory.join(safe_entry_path(name)?);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    File::create(path)
+}
+
+// Archive output that can still be read after the archive takes the writer
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub(crate) struct SharedBuffer(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+#[cfg(test)]
+impl SharedBuffer {
+    pub(crate) fn take(&self) -> Vec<u8> {
+        self.0.take()
+    }
+}
+
+#[cfg(test)]
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn safe_entry_paths() {
+        assert_eq!(
+            safe_entry_path("a/./b.png").unwrap(),
+            Path::new("a/./b.png")
+        );
+        for name in ["../a.png", "a/../../b.png", "/etc/passwd"] {
+            let error = safe_entry_path(name).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                format!("Refusing to extract unsafe path '{name}'")
+            );
+        }
+    }
+
+    fn names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|x| x.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn atomic_file_commit() {
+        let dir = TempDir::new("mkcbt-test").unwrap();
+        let target = dir.path().join("a.cbt");
+        fs::write(&target, b"old").unwrap();
+        let file = AtomicFile::create(&target).unwrap();
+        file.writer().unwrap().write_all(b"new").unwrap();
+        assert_eq!(fs::read(&target).unwrap(), b"old");
+        assert_eq!(names(dir.path()).len(), 2);
+        file.commit().unwrap();
+        assert_eq!(fs::read(&target).unwrap(), b"new");
+        assert_eq!(names(dir.path()), ["a.cbt"]);
+    }
+
+    #[test]
+    fn atomic_file_dropped() {
+        let dir = TempDir::new("mkcbt-test").unwrap();
+        let target = dir.path().join("a.cbt");
+        fs::write(&target, b"old").unwrap();
+        let file = AtomicFile::create(&target).unwrap();
+        file.writer().unwrap().write_all(b"partial").unwrap();
+        drop(file);
+        assert_eq!(fs::read(&target).unwrap(), b"old");
+        assert_eq!(names(dir.path()), ["a.cbt"]);
+
+        // No target is created either
+        let target = dir.path().join("b.cbt");
+        drop(AtomicFile::create(&target).unwrap());
+        assert_eq!(names(dir.path()), ["a.cbt"]);
+    }
+
+    #[test]
+    fn atomic_file_not_a_name() {
+        let error = AtomicFile::create("/").err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,445 @@
+//
+// Copyright 2024-2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+use std::fs::{self, File};
+use std::io::{Read, Result};
+use std::path::Path;
+
+/// Image formats, detected by content
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImageFormat {
+    Jpeg,
+    Png,
+    Webp,
+    Gif,
+    Bmp,
+    Tiff,
+    Avif,
+    Jxl,
+}
+
+impl ImageFormat {
+    fn sniff(data: &[u8]) -> Option<Self> {
+        // Brands of the ISOBMFF file type box
+        let brands = || {
+            isobmff_boxes(data, b"ftyp")
+                .into_iter()
+                .next()
+                .into_iter()
+                .flat_map(|ftyp| ftyp.chunks_exact(4).filter(|x| x.len() == 4))
+        };
+        if data.starts_with(b"\xff\xd8\xff") {
+            Some(Self::Jpeg)
+        } else if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(Self::Png)
+        } else if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+            Some(Self::Webp)
+        } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+            Some(Self::Gif)
+        } else if data.starts_with(b"BM") && data.len() >= 26 {
+            Some(Self::Bmp)
+        } else if data.starts_with(b"II*\0") || data.starts_with(b"MM\0*") {
+            Some(Self::Tiff)
+        } else if data.starts_with(b"\xff\x0a")
+            || data.starts_with(b"\0\0\0\x0cJXL \x0d\x0a\x87\x0a")
+        {
+            Some(Self::Jxl)
+        } else if brands().any(|brand| brand == b"avif" || brand == b"avis") {
+            Some(Self::Avif)
+        } else {
+            None
+        }
+    }
+
+    pub fn detect(path: &Path) -> Result<Option<Self>> {
+        let mut header = Vec::with_capacity(64);
+        File::open(path)?.take(64).read_to_end(&mut header)?;
+        Ok(Self::sniff(&header))
+    }
+
+    /// By extension, for entries that are listed without being read
+    pub fn from_name(name: &str) -> Option<Self> {
+        let ext = Path::new(name).extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "jpg" | "jpeg" => Some(Self::Jpeg),
+            "png" => Some(Self::Png),
+            "webp" => Some(Self::Webp),
+            "gif" => Some(Self::Gif),
+            "bmp" => Some(Self::Bmp),
+            "tif" | "tiff" => Some(Self::Tiff),
+            "avif" => Some(Self::Avif),
+            "jxl" => Some(Self::Jxl),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpg",
+            Self::Png => "png",
+            Self::Webp => "webp",
+            Self::Gif => "gif",
+            Self::Bmp => "bmp",
+            Self::Tiff => "tif",
+            Self::Avif => "avif",
+            Self::Jxl => "jxl",
+        }
+    }
+}
+
+// Image dimensions
+pub(crate) fn image_dimensions(path: &Path) -> Option<(u32, u32)> {
+    dimensions(&fs::read(path).ok()?)
+}
+
+fn dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    let le16 = |i: usize| Some(u16::from_le_bytes(data.get(i..i + 2)?.try_into().ok()?) as u32);
+    let be32 = |i: usize| Some(u32::from_be_bytes(data.get(i..i + 4)?.try_into().ok()?));
+    let le32 = |i: usize| Some(u32::from_le_bytes(data.get(i..i + 4)?.try_into().ok()?));
+    match ImageFormat::sniff(data)? {
+        ImageFormat::Png => Some((be32(16)?, be32(20)?)),
+        ImageFormat::Gif => Some((le16(6)?, le16(8)?)),
+        ImageFormat::Bmp => {
+            let width = (le32(18)? as i32).unsigned_abs();
+            let height = (le32(22)? as i32).unsigned_abs();
+            Some((width, height))
+        }
+        ImageFormat::Jpeg => jpeg_dimensions(data),
+        ImageFormat::Webp => webp_dimensions(data),
+        ImageFormat::Jxl if data.starts_with(b"\xff\x0a") => jxl_dimensions(&data[2..]),
+        ImageFormat::Jxl => {
+            let codestream = match isobmff_boxes(data, b"jxlc").into_iter().next() {
+                Some(jxlc) => jxlc,
+                None => isobmff_boxes(data, b"jxlp").into_iter().next()?.get(4..)?,
+            };
+            jxl_dimensions(codestream.strip_prefix(b"\xff\x0a")?)
+        }
+        ImageFormat::Avif => avif_dimensions(data),
+        ImageFormat::Tiff => None,
+    }
+}
+
+// Frame header of the first start-of-frame marker
+fn jpeg_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    let mut i = 2;
+    while i + 9 < data.len() {
+        if data[i] != 0xff {
+            return None;
+        }
+        let marker = data[i + 1];
+        if marker == 0xff {
+            i += 1;
+            continue;
+        }
+        let len = u16::from_be_bytes([data[i + 2], data[i + 3]]) as usize;
+        if (0xc0..=0xcf).contains(&marker) && !matches!(marker, 0xc4 | 0xc8 | 0xcc) {
+            let height = u16::from_be_bytes([data[i + 5], data[i + 6]]) as u32;
+            let width = u16::from_be_bytes([data[i + 7], data[i + 8]]) as u32;
+            return Some((width, height));
+        }
+        i += 2 + len;
+    }
+    None
+}
+
+fn webp_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    let chunk = data.get(12..16)?;
+    let body = data.get(20..30)?;
+    let le24 = |x: &[u8]| x[0] as u32 | (x[1] as u32) << 8 | (x[2] as u32) << 16;
+    match chunk {
+        b"VP8 " => Some((
+            u16::from_le_bytes([body[6], body[7]]) as u32 & 0x3fff,
+            u16::from_le_bytes([body[8], body[9]]) as u32 & 0x3fff,
+        )),
+        b"VP8L" => {
+            let bits = u32::from_le_bytes(body[1..5].try_into().ok()?);
+            Some(((bits & 0x3fff) + 1, ((bits >> 14) & 0x3fff) + 1))
+        }
+        b"VP8X" => Some((le24(&body[4..7]) + 1, le24(&body[7..10]) + 1)),
+        _ => None,
+    }
+}
+
+// SizeHeader at the start of a JPEG XL codestream (after the signature)
+fn jxl_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    let mut position = 0;
+    let mut bits = |count: usize| -> Option<u32> {
+        let mut value = 0;
+        for i in 0..count {
+            let byte = *data.get((position + i) / 8)?;
+            value |= (((byte >> ((position + i) % 8)) & 1) as u32) << i;
+        }
+        position += count;
+        Some(value)
+    };
+    let size = |bits: &mut dyn FnMut(usize) -> Option<u32>| -> Option<u32> {
+        let count = [9, 13, 18, 30][bits(2)? as usize];
+        Some(bits(count)? + 1)
+    };
+    let small = bits(1)? == 1;
+    let height = if small {
+        (bits(5)? + 1) * 8
+    } else {
+        size(&mut bits)?
+    };
+    let ratio = bits(3)?;
+    let width = match ratio {
+        0 if small => (bits(5)? + 1) * 8,
+        0 => size(&mut bits)?,
+        _ => {
+            let (numerator, denominator) =
+                [(1, 1), (12, 10), (4, 3), (3, 2), (16, 9), (5, 4), (2, 1)][ratio as usize - 1];
+            (height as u64 * numerator / denominator) as u32
+        }
+    };
+    Some((width, height))
+}
+
+// Child boxes of the given type in an ISOBMFF container
+fn isobmff_boxes<'a>(mut data: &'a [u8], kind: &[u8; 4]) -> Vec<&'a [u8]> {
+    let mut found = Vec::new();
+    while data.len() >= 8 {
+        let size = u32::from_be_bytes(data[..4].try_into().unwrap()) as u64;
+        let (header_len, size) = match size {
+            0 => (8, data.len() as u64),
+            1 if data.len() >= 16 => (16, u64::from_be_bytes(data[8..16].try_into().unwrap())),
+            _ => (8, size),
+        };
+        if size < header_len || size > data.len() as u64 {
+            break;
+        }
+        if &data[4..8] == kind {
+            found.push(&data[header_len as usize..size as usize]);
+        }
+        data = &data[size as usize..];
+    }
+    found
+}
+
+// Largest image spatial extent, which is the primary image for grids
+fn avif_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    let meta = isobmff_boxes(data, b"meta").into_iter().next()?.get(4..)?;
+    let iprp = isobmff_boxes(meta, b"iprp").into_iter().next()?;
+    let ipco = isobmff_boxes(iprp, b"ipco").into_iter().next()?;
+    isobmff_boxes(ipco, b"ispe")
+        .into_iter()
+        .filter(|ispe| ispe.len() >= 12)
+        .map(|ispe| {
+            (
+                u32::from_be_bytes(ispe[4..8].try_into().unwrap()),
+                u32::from_be_bytes(ispe[8..12].try_into().unwrap()),
+            )
+        })
+        .max_by_key(|&(width, height)| width as u64 * height as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Name, data, format and dimensions of a sample image header
+    type Fixture = (&'static str, Vec<u8>, ImageFormat, Option<(u32, u32)>);
+
+    fn isobmff_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        [&(body.len() as u32 + 8).to_be_bytes()[..], kind, body].concat()
+    }
+
+    // Fields written least significant bit first, as in JPEG XL headers
+    fn bit_fields(fields: &[(u32, usize)]) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut position = 0;
+        for &(value, count) in fields {
+            for i in 0..count {
+                if position % 8 == 0 {
+                    data.push(0);
+                }
+                *data.last_mut().unwrap() |= (((value >> i) & 1) as u8) << (position % 8);
+                position += 1;
+            }
+        }
+        data
+    }
+
+    fn webp(chunk: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let len = (body.len() as u32).to_le_bytes();
+        [b"RIFF", &[0; 4][..], b"WEBP", chunk, &len, body].concat()
+    }
+
+    fn fixtures() -> Vec<Fixture> {
+        let png = [
+            &b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR"[..],
+            &800u32.to_be_bytes(),
+            &1200u32.to_be_bytes(),
+            b"\x08\x02\0\0\0",
+        ]
+        .concat();
+        let gif = [
+            &b"GIF89a"[..],
+            &320u16.to_le_bytes(),
+            &200u16.to_le_bytes(),
+            &[0; 7],
+        ]
+        .concat();
+        // Negative height for rows stored top down
+        let bmp = [
+            &b"BM"[..],
+            &[0; 16],
+            &640i32.to_le_bytes(),
+            &(-480i32).to_le_bytes(),
+            &[0; 4],
+        ]
+        .concat();
+        // Fill bytes and a Huffman table before a progressive frame header
+        let jpeg = [
+            &b"\xff\xd8\xff\xe0\x00\x10JFIF\0\x01\x01\0\0\x01\0\x01\0\0"[..],
+            b"\xff\xff\xc4\x00\x04\0\0",
+            b"\xff\xc2\x00\x11\x08",
+            &768u16.to_be_bytes(),
+            &1024u16.to_be_bytes(),
+            &[3, 1, 0x22, 0, 2, 0x11, 1, 3, 0x11, 1],
+        ]
+        .concat();
+        // Horizontal and vertical scale in the top bits of VP8 sizes
+        let vp8 = webp(
+            b"VP8 ",
+            &[
+                &[0x30, 0x01, 0, 0x9d, 0x01, 0x2a][..],
+                &(0x4000u16 | 640).to_le_bytes(),
+                &(0x8000u16 | 480).to_le_bytes(),
+            ]
+            .concat(),
+        );
+        let vp8l_bits = 399 | (299 << 14) | (1 << 28);
+        let vp8l = webp(
+            b"VP8L",
+            &[&[0x2f][..], &u32::to_le_bytes(vp8l_bits), &[0; 5]].concat(),
+        );
+        let vp8x = webp(
+            b"VP8X",
+            &[
+                &[0x10, 0, 0, 0][..],
+                &4999u32.to_le_bytes()[..3],
+                &2999u32.to_le_bytes()[..3],
+            ]
+            .concat(),
+        );
+        let jxl_small = [
+            &b"\xff\x0a"[..],
+            &bit_fields(&[(1, 1), (7, 5), (0, 3), (15, 5)]),
+        ]
+        .concat();
+        // Width from a 16:9 aspect ratio
+        let jxl_ratio = [
+            &b"\xff\x0a"[..],
+            &bit_fields(&[(0, 1), (1, 2), (1079, 13), (5, 3)]),
+        ]
+        .concat();
+        let size_header = bit_fields(&[(0, 1), (1, 2), (1199, 13), (0, 3), (1, 2), (799, 13)]);
+        let codestream = [&b"\xff\x0a"[..], &size_header].concat();
+        let jxl_container = |kind: &[u8; 4], prefix: &[u8]| {
+            [
+                &b"\0\0\0\x0cJXL \x0d\x0a\x87\x0a"[..],
+                &isobmff_box(b"ftyp", b"jxl \0\0\0\0jxl "),
+                &isobmff_box(kind, &[prefix, &codestream].concat()),
+            ]
+            .concat()
+        };
+        // Grid tiles are smaller than the primary image
+        let ispe = |width: u32, height: u32| {
+            isobmff_box(
+                b"ispe",
+                &[&[0; 4][..], &width.to_be_bytes(), &height.to_be_bytes()].concat(),
+            )
+        };
+        let ipco = isobmff_box(b"ipco", &[ispe(512, 384), ispe(1024, 768)].concat());
+        let meta = isobmff_box(
+            b"meta",
+            &[&[0; 4][..], &isobmff_box(b"iprp", &ipco)].concat(),
+        );
+        let avif = [isobmff_box(b"ftyp", b"avif\0\0\0\0mif1miaf"), meta].concat();
+
+        vec![
+            ("png", png, ImageFormat::Png, Some((800, 1200))),
+            ("gif", gif, ImageFormat::Gif, Some((320, 200))),
+            ("bmp", bmp, ImageFormat::Bmp, Some((640, 480))),
+            ("jpeg", jpeg, ImageFormat::Jpeg, Some((1024, 768))),
+            ("vp8", vp8, ImageFormat::Webp, Some((640, 480))),
+            ("vp8l", vp8l, ImageFormat::Webp, Some((400, 300))),
+            ("vp8x", vp8x, ImageFormat::Webp, Some((5000, 3000))),
+            ("jxl small", jxl_small, ImageFormat::Jxl, Some((128, 64))),
+            ("jxl ratio", jxl_ratio, ImageFormat::Jxl, Some((1920, 1080))),
+            (
+                "jxl",
+                codestream.clone(),
+                ImageFormat::Jxl,
+                Some((800, 1200)),
+            ),
+            (
+                "jxlc",
+                jxl_container(b"jxlc", b""),
+                ImageFormat::Jxl,
+                Some((800, 1200)),
+            ),
+            (
+                "jxlp",
+                jxl_container(b"jxlp", &[0; 4]),
+                ImageFormat::Jxl,
+                Some((800, 1200)),
+            ),
+            ("avif", avif, ImageFormat::Avif, Some((1024, 768))),
+            ("tiff", b"II*\0\x08\0\0\0".to_vec(), ImageFormat::Tiff, None),
+        ]
+    }
+
+    #[test]
+    fn sniff_and_dimensions() {
+        for (name, data, format, size) in fixtures() {
+            assert_eq!(ImageFormat::sniff(&data), Some(format), "{name}");
+            assert_eq!(dimensions(&data), size, "{name}");
+        }
+        for data in [
+            &b""[..],
+            b"\xff\xd8",
+            b"RIFF\0\0\0\0WEBX",
+            b"BM\0\0",
+            b"\0\0\0\x08ftyp",
+        ] {
+            assert_eq!(ImageFormat::sniff(data), None);
+        }
+        let heic = isobmff_box(b"ftyp", b"heic\0\0\0\0mif1heic");
+        assert_eq!(ImageFormat::sniff(&heic), None);
+    }
+
+    // Short reads never panic, nor give other dimensions
+    #[test]
+    fn truncated_headers() {
+        for (name, data, _, size) in fixtures() {
+            for len in 0..data.len() {
+                let found = dimensions(&data[..len]);
+                assert!(
+                    found.is_none() || found == size,
+                    "{name} at {len}: {found:?}"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,257 @@
+//
+// Copyright 2024-2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+use std::fs::{self, File};
+use std::io::{BufReader, Error, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering as AtomicOrdering;
+
+use crate::archive::ArchiveFormat;
+use crate::files::{TempDir, create_entry_file};
+use crate::image::ImageFormat;
+use crate::signal::SIGNAL;
+use crate::sort::{SortMode, natural_cmp, page_file_name, sort_pages};
+use crate::tar::SimpleTarReader;
+use crate::zip::SimpleZipReader;
+
+/// Input collection
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum NonImagePolicy {
+    Ignore,
+    #[default]
+    Warn,
+    Error,
+}
+
+impl NonImagePolicy {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "ignore" => Ok(Self::Ignore),
+            "warn" => Ok(Self::Warn),
+            "error" => Ok(Self::Error),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unknown non-image policy '{name}' (expected one of: ignore, warn, error)"),
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct InputOptions {
+    pub(crate) sort_mode: SortMode,
+    pub(crate) non_images: NonImagePolicy,
+    pub(crate) recursive: bool,
+}
+
+// Pages in subdirectories go into a chapter named after the relative path
+#[derive(Clone)]
+pub(crate) struct InputPage {
+    pub(crate) path: PathBuf,
+    pub(crate) format: ImageFormat,
+    pub(crate) chapter: String,
+}
+
+impl AsRef<Path> for InputPage {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+pub(crate) struct Inputs {
+    pub(crate) pages: Vec<InputPage>,
+    pub(crate) comic_info: Option<PathBuf>,
+    options: InputOptions,
+    unpacked: Vec<TempDir>,
+    archive: Option<(PathBuf, PathBuf)>,
+    pub(crate) warnings: Vec<String>,
+}
+
+impl Inputs {
+    pub(crate) fn collect(paths: Vec<PathBuf>, options: InputOptions) -> Result<Self> {
+        let mut inputs = Self {
+            pages: Vec::new(),
+            comic_info: None,
+            options,
+            unpacked: Vec::new(),
+            archive: None,
+            warnings: Vec::new(),
+        };
+        for path in paths {
+            if !path.exists() {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("'{}' does not exist", path.display()),
+                ));
+            }
+            if path.is_dir() {
+                inputs.collect_dir(&path, "", false)?;
+            } else if let Some(format) = ArchiveFormat::detect(&path)? {
+                inputs.collect_archive(&path, format)?;
+            } else {
+                let page = inputs.classify(path, "")?;
+                inputs.pages.extend(page);
+            }
+        }
+        Ok(inputs)
+    }
+
+    // Archives are unpacked and read like a directory, with subdirectories
+    // flattened into the page order unless they are chapters
+    fn collect_archive(&mut self, path: &Path, format: ArchiveFormat) -> Result<()> {
+        let dir = TempDir::new("mkcbt-input")?;
+        match format {
+            ArchiveFormat::Cbt => {
+                let mut tar = SimpleTarReader::new(BufReader::new(File::open(path)?));
+                while let Some(entry) = tar.next_entry()? {
+                    Self::check_signal()?;
+                    if entry.is_file() {
+                        let mut file = create_entry_file(dir.path(), &entry.name)?;
+                        tar.copy_data(&entry, &mut file)?;
+                    }
+                }
+            }
+            ArchiveFormat::Cbz => {
+                let mut zip = SimpleZipReader::new(BufReader::new(File::open(path)?))?;
+                let entries = std::mem::take(&mut zip.entries);
+                for entry in entries.iter().filter(|x| !x.name.ends_with('/')) {
+                    Self::check_signal()?;
+                    let data = zip.read_data(entry)?;
+                    create_entry_file(dir.path(), &entry.name)?.write_all(&data)?;
+                }
+            }
+        }
+
+        // Skip a single folder wrapping everything
+        let mut root = dir.path().to_path_buf();
+        loop {
+            let entries: Vec<_> = fs::read_dir(&root)?.collect::<Result<_>>()?;
+            match &entries[..] {
+                [entry] if entry.file_type()?.is_dir() => root = entry.path(),
+                _ => break,
+            }
+        }
+        self.archive = Some((dir.path().to_path_buf(), path.to_path_buf()));
+        let result = self.collect_dir(&root, "", true);
+        self.archive = None;
+        self.unpacked.push(dir);
+        result
+    }
+
+    pub(crate) fn close(self) -> Result<()> {
+        for mut dir in self.unpacked {
+            dir.close()?;
+        }
+        Ok(())
+    }
+
+    // Large archives can take a while to unpack
+    fn check_signal() -> Result<()> {
+        match SIGNAL.load(AtomicOrdering::SeqCst) {
+            0 => Ok(()),
+            _ => Err(ErrorKind::Interrupted.into()),
+        }
+    }
+
+    // Files unpacked from archives are shown as ARCHIVE:ENTRY
+    fn display(&self, path: &Path) -> String {
+        match &self.archive {
+            Some((dir, archive)) => match path.strip_prefix(dir) {
+                Ok(entry) => format!("{}:{}", archive.display(), entry.display()),
+                Err(_) => path.display().to_string(),
+            },
+            None => path.display().to_string(),
+        }
+    }
+
+    // Pages of a directory come before those of its subdirectories
+    fn collect_dir(&mut self, dir: &Path, chapter: &str, flatten: bool) -> Result<()> {
+        let mut pages = Vec::new();
+        let mut subdirs = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            // Hidden files are never pages
+            if entry.file_name().as_encoded_bytes().starts_with(b".") {
+                continue;
+            }
+            if path.is_file() {
+                pages.extend(self.classify(path, chapter)?);
+            } else if (self.options.recursive || flatten) && entry.file_type()?.is_dir() {
+                subdirs.push(path);
+            }
+        }
+        sort_pages(&mut pages, &self.options.sort_mode)?;
+        self.pages.append(&mut pages);
+
+        subdirs.sort_by(|a, b| natural_cmp(&page_file_name(a), &page_file_name(b)));
+        for subdir in subdirs {
+            let name = page_file_name(&subdir);
+            if !self.options.recursive {
+                self.collect_dir(&subdir, chapter, flatten)?;
+            } else if chapter.is_empty() {
+                self.collect_dir(&subdir, &name, flatten)?;
+            } else {
+                self.collect_dir(&subdir, &format!("{chapter}/{name}"), flatten)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Images become pages, and everything else is handled by the policy
+    fn classify(&mut self, path: PathBuf, chapter: &str) -> Result<Option<InputPage>> {
+        if let Some(format) = ImageFormat::detect(&path)? {
+            return Ok(Some(InputPage {
+                path,
+                format,
+                chapter: chapter.to_string(),
+            }));
+        }
+        if path
+            .file_name()
+            .is_some_and(|name| name.eq_ignore_ascii_case("ComicInfo.xml"))
+        {
+            match self.comic_info {
+                Some(_) => {
+                    let warning = format!("Ignoring additional '{}'", self.display(&path));
+                    self.warnings.push(warning);
+                }
+                None => self.comic_info = Some(path),
+            }
+            return Ok(None);
+        }
+        match self.options.non_images {
+            NonImagePolicy::Ignore => {}
+            NonImagePolicy::Warn => {
+                let warning = format!("Skipping non-image file '{}'", self.display(&path));
+                self.warnings.push(warning);
+            }
+            NonImagePolicy::Error => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("'{}' is not a supported image", self.display(&path)),
+                ));
+            }
+        }
+        Ok(None)
+    }
+}
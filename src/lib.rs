@@ -0,0 +1,73 @@
+//
+// Copyright 2024-2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+//! Packing images into comic book archives (CBT, or CBZ), converting pages
+//! with external encoders.
+//!
+//! ```no_run
+//! use mkcbt::{CbtBuilder, Encoder, EncoderSettings};
+//!
+//! let summary = CbtBuilder::new()
+//!     .input("scans/")
+//!     .encoder(Encoder::Avif, EncoderSettings::preset("balanced")?)
+//!     .on_event(|event| {
+//!         if let mkcbt::CbtEvent::PageWritten { name, size, .. } = event {
+//!             println!("{name}: {size} bytes");
+//!         }
+//!     })
+//!     .create("comic.cbt")?;
+//! println!("{} pages", summary.pages);
+//! # Ok::<(), mkcbt::CbtError>(())
+//! ```
+
+mod archive;
+mod builder;
+mod cache;
+mod comicinfo;
+mod encoder;
+mod error;
+mod files;
+mod image;
+mod inputs;
+mod progress;
+mod reader;
+mod regex;
+mod signal;
+mod sort;
+mod state;
+mod tar;
+mod writer;
+mod zip;
+
+pub use archive::ArchiveFormat;
+pub use builder::CbtBuilder;
+pub use cache::{CacheEntry, EncodeCache};
+pub use comicinfo::ComicInfo;
+pub use encoder::{Encoder, EncoderSettings};
+pub use error::{CbtError, CbtResult};
+pub use image::ImageFormat;
+pub use inputs::NonImagePolicy;
+pub use reader::CbtReader;
+pub use signal::catch_signals;
+pub use sort::SortMode;
+pub use tar::{SimpleTarArchive, TarEntry};
+pub use writer::{CbtEvent, CbtSummary, CbtWriter, FailurePolicy, PageFailure};
@@ -20,294 +20,831 @@
 // IN THE SOFTWARE.
 //
 
-use std::collections::VecDeque;
 use std::fs::File;
-use std::io::{Error, ErrorKind, Result, Write};
+use std::io::{BufReader, Error, ErrorKind, IsTerminal, Read, Result, Write};
 use std::path::{Path, PathBuf};
-use std::process::{Child, Command, ExitCode, Stdio};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::process::ExitCode;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{env, fs};
 
-// Temporary directories
-struct TempDir {
-    path: PathBuf,
+use mkcbt::{
+    ArchiveFormat, CbtBuilder, CbtError, CbtEvent, CbtReader, CbtResult, CbtSummary, ComicInfo,
+    EncodeCache, Encoder, EncoderSettings, FailurePolicy, ImageFormat, NonImagePolicy, SortMode,
+    TarEntry, catch_signals,
+};
+
+// Progress display, as a status line redrawn on terminals or one line per page
+#[derive(Clone, Copy, PartialEq)]
+enum ProgressMode {
+    Auto,
+    Bar,
+    Lines,
+    Off,
 }
 
-impl TempDir {
-    fn new(prefix: &str) -> Self {
-        let mut time_val = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .subsec_nanos();
-        let mut path = env::temp_dir().join(format!("{prefix}-{:08x}", time_val));
-        while path.exists() {
-            time_val = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .subsec_nanos();
-            path = env::temp_dir().join(format!("{prefix}-{:08x}", time_val));
+impl ProgressMode {
+    fn parse(name: &str) -> Result<Self> {
+        match name {
+            "auto" => Ok(Self::Auto),
+            "bar" => Ok(Self::Bar),
+            "lines" => Ok(Self::Lines),
+            "off" | "none" => Ok(Self::Off),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unknown progress mode '{name}' (expected one of: auto, bar, lines, off)"),
+            )),
         }
-        fs::create_dir(&path).expect("Could not create temporary directory");
-        Self { path }
     }
 
-    fn path(&self) -> &Path {
-        self.path.as_path()
+    // Status line on a terminal, nothing otherwise
+    fn resolve(self) -> Self {
+        match self {
+            Self::Auto if std::io::stderr().is_terminal() => Self::Bar,
+            Self::Auto => Self::Off,
+            mode => mode,
+        }
     }
 }
 
-impl Drop for TempDir {
-    fn drop(&mut self) {
-        fs::remove_dir_all(&self.path).expect("Could not remove temporary directory");
-    }
+// Command line arguments, with options as --name VALUE or --name=VALUE
+enum Arg {
+    Option(String),
+    Positional(String),
 }
 
-// Basic TAR files
-struct SimpleTarArchive {
-    writer: Box<dyn Write>,
+struct ArgParser {
+    args: std::vec::IntoIter<String>,
+    option: String,
+    value: Option<String>,
+    positional_only: bool,
 }
 
-impl SimpleTarArchive {
-    const ZEROS: [u8; 1024] = [0; 1024];
-
-    fn new(writer: impl Write + 'static) -> Self {
+impl ArgParser {
+    fn new(args: Vec<String>) -> Self {
         Self {
-            writer: Box::new(writer),
+            args: args.into_iter(),
+            option: String::new(),
+            value: None,
+            positional_only: false,
         }
     }
 
-    fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
-        Ok(Self::new(File::create(path)?))
+    fn next(&mut self) -> Result<Option<Arg>> {
+        if self.value.is_some() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Option '{}' does not take a value", self.option),
+            ));
+        }
+        let Some(arg) = self.args.next() else {
+            return Ok(None);
+        };
+        if self.positional_only || arg == "-" || !arg.starts_with('-') {
+            return Ok(Some(Arg::Positional(arg)));
+        }
+        if arg == "--" {
+            self.positional_only = true;
+            return self.next();
+        }
+        self.option = match arg.split_once('=') {
+            Some((name, value)) => {
+                self.value = Some(value.to_string());
+                name.to_string()
+            }
+            None => arg,
+        };
+        Ok(Some(Arg::Option(self.option.clone())))
     }
 
-    fn write_file<P: AsRef<Path>>(&mut self, path: P, file_name: &str) -> Result<()> {
-        let file_len = path.as_ref().metadata()?.len();
-        let mut file = File::open(path)?;
+    fn value(&mut self) -> Result<String> {
+        self.value
+            .take()
+            .or_else(|| self.args.next())
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Option '{}' requires a value", self.option),
+                )
+            })
+    }
 
-        // Create header
-        let mut header = [0; 512];
-        header[..file_name.len()].copy_from_slice(file_name.as_bytes()); // Filename
-        header[100..107].copy_from_slice(b"0000444"); // Permissions
-        header[108..115].copy_from_slice(b"0000000"); // Owner ID
-        header[116..123].copy_from_slice(b"0000000"); // Group ID
-        header[124..135].copy_from_slice(format!("{:011o}", file_len).as_bytes()); // File size
-        header[136..147].copy_from_slice(b"00000000000"); // Modification time
-        header[148..156].copy_from_slice(b"        "); // Checksum (for now)
-        header[156] = b'0'; // Link indicator
-        header[257..262].copy_from_slice(b"ustar"); // UStar indicator
-        header[263..265].copy_from_slice(b"00"); // UStar version
+    fn number<T: std::str::FromStr + PartialOrd + std::fmt::Display>(
+        &mut self,
+        min: T,
+        max: T,
+    ) -> Result<T> {
+        let value = self.value()?;
+        value
+            .parse()
+            .ok()
+            .filter(|x| *x >= min && *x <= max)
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "Option '{}' requires a number from {min} to {max}, got '{value}'",
+                        self.option
+                    ),
+                )
+            })
+    }
 
-        // Calculate checksum
-        let checksum: u32 = header.iter().map(|&x| x as u32).sum();
-        header[148..155].copy_from_slice(format!("{:06o}\0", checksum).as_bytes());
+    // Byte count with an optional K, M or G suffix
+    fn size(&mut self) -> Result<u64> {
+        let value = self.value()?;
+        let (number, shift) = match value.char_indices().last() {
+            Some((i, 'K' | 'k')) => (&value[..i], 10),
+            Some((i, 'M' | 'm')) => (&value[..i], 20),
+            Some((i, 'G' | 'g')) => (&value[..i], 30),
+            _ => (value.as_str(), 0),
+        };
+        number
+            .parse::<u64>()
+            .ok()
+            .and_then(|number| number.checked_mul(1 << shift))
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "Option '{}' requires a size such as 500M, got '{value}'",
+                        self.option
+                    ),
+                )
+            })
+    }
 
-        // Write header
-        self.writer.write_all(&header)?;
+    fn unknown(&self) -> Error {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("Unknown option '{}'", self.option),
+        )
+    }
+}
+
+const USAGE: &str = "USAGE: mkcbt [create] [OPTIONS] OUTPUT.cbt|OUTPUT.cbz INPUTS...
+       mkcbt append [OPTIONS] ARCHIVE.cbt INPUTS...
+       mkcbt list ARCHIVE.cbt
+       mkcbt extract ARCHIVE.cbt [DIRECTORY]
+       mkcbt info ARCHIVE.cbt
+       mkcbt cache list|prune|clear [CACHE OPTIONS]
+
+INPUTS are images, directories of images, or CBT/CBZ/TAR/ZIP archives.
+Appending continues the page numbers of ARCHIVE, renaming its pages when the
+numbers need more digits; ComicInfo.xml options do not apply.
+
+OPTIONS:
+    --format cbt|cbz        Archive format (default: from OUTPUT extension, else cbt)
+    --sort natural|name|mtime|exif
+                            Order of directory contents (default: natural)
+    --sort-regex REGEX      Order directory contents by the page number in the
+                            first capture group (or whole match) of REGEX
+    --recursive             Include subdirectories as chapter directories
+    --non-images ignore|warn|error
+                            Handling of files that are not images (default: warn);
+                            hidden files are always skipped and ComicInfo.xml is
+                            kept unless metadata is generated
+    --encoder avif|jxl|webp|copy
+                            Page format, or copy to keep the originals (default: avif);
+                            pages the encoder can not read are kept as they are
+    --preset archive|balanced|fast
+                            Encoder preset (default: archive)
+    --quality 0-100         Encoder quality
+    --speed 0-10            Encoder speed (slowest is 0)
+    --lossless              Encode losslessly
+    --min-quantizer 0-63    Minimum quantizer (avif)
+    --max-quantizer 0-63    Maximum quantizer (avif)
+    --yuv 444|422|420|400   YUV subsampling (avif)
+    --depth 8|10|12         Bit depth (avif)
+    --lossless-jpeg         Recompress JPEG pages losslessly (jxl)
+    --cache                 Reuse pages encoded by earlier runs from the cache
+                            (default: $XDG_CACHE_HOME/mkcbt or ~/.cache/mkcbt)
+    --cache-dir DIR         Use DIR as the cache (implies --cache)
+    --resume                Keep encoded pages in a state directory until the archive
+                            is done, and reuse those left by an interrupted run
+    --state-dir DIR         State directory (default: OUTPUT.mkcbt-state)
+    --keep-smaller          Store the original image when the encoded page is not smaller
+    --min-savings PERCENT   Store the original unless the encoded page is at least
+                            PERCENT smaller (implies --keep-smaller)
+    --jobs N                Pages converted at once (default: number of CPUs)
+    --threads-per-job N     Threads given to each encoder (default: 1)
+    --nice 0-19             Run mkcbt and its encoders at lowered CPU priority
+    --idle-io               Only use the disk when it is otherwise idle (Linux)
+    --max-ahead PAGES       Pages that may be converted ahead of the oldest unwritten
+                            one (default: 4 per CPU)
+    --progress auto|bar|lines|off
+                            Progress display (default: auto, a status line when
+                            standard error is a terminal)
+    --json-events           Write newline-delimited JSON events to standard error
+                            instead of messages (submitted, encoder_started,
+                            encoder_finished, page_written, warning, summary, error)
+    --json-events-fd FD     Write the JSON events to file descriptor FD
+    --on-error abort|store|skip
+                            Handling of pages the encoder fails on (default: abort);
+                            store keeps the original image, skip leaves the page out,
+                            and either exits with status 2 once the archive is done
+    --keep-going            Same as --on-error store
+    --failure-report FILE   Write the pages that could not be converted to FILE
+    --comicinfo             Write ComicInfo.xml with the page list
+    --bookmarks             Bookmark the first page of each chapter in ComicInfo.xml
+    --metadata FILE         Read ComicInfo.xml fields from FILE (Field=Value lines)
+    --meta FIELD=VALUE      Set any ComicInfo.xml field
+    --title, --series, --number, --count, --volume, --summary, --year, --month,
+    --day, --writer, --penciller, --inker, --colorist, --letterer,
+    --cover-artist, --editor, --translator, --publisher, --genre, --tags, --web,
+    --language, --age-rating TEXT
+                            Set the corresponding ComicInfo.xml field
+    --manga yes|no|rtl      Set the manga reading direction
 
-        // Copy file
-        std::io::copy(&mut file, &mut self.writer)?;
+CACHE OPTIONS:
+    --cache-dir DIR         Cache to use (default as above)
+    --max-size SIZE         Prune least recently used pages down to SIZE bytes,
+                            with an optional K, M or G suffix
+    --max-age DAYS          Prune pages not used for more than DAYS days";
+
+fn open_archive(path: &str) -> Result<CbtReader<Box<dyn Read>>> {
+    if path == "-" {
+        Ok(CbtReader::new(Box::new(std::io::stdin().lock())))
+    } else {
+        Ok(CbtReader::new(Box::new(BufReader::new(File::open(path)?))))
+    }
+}
+
+// Newline-delimited JSON events for --json-events, on standard error or
+// another file descriptor
+struct EventLog {
+    writer: Box<dyn Write + Send>,
+    stderr: bool,
+}
+
+static EVENT_LOG: Mutex<Option<EventLog>> = Mutex::new(None);
+
+// Fields are given as encoded JSON values
+fn log_event(event: &str, fields: &[(&str, String)]) {
+    let mut log = EVENT_LOG.lock().unwrap();
+    if let Some(log) = log.as_mut() {
+        let mut line = format!("{{\"event\":{}", json_string(event));
+        for (name, value) in fields {
+            line.push_str(&format!(",{}:{value}", json_string(name)));
+        }
+        line.push('}');
+        let _ = writeln!(log.writer, "{line}");
+        let _ = log.writer.flush();
+    }
+}
 
-        // Add padding
-        if file_len % 512 != 0 {
-            self.writer
-                .write_all(&Self::ZEROS[..(512 - file_len % 512) as usize])?;
+fn json_string(value: &str) -> String {
+    let mut json = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c < ' ' => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
         }
+    }
+    json.push('"');
+    json
+}
+
+fn events_enabled() -> bool {
+    EVENT_LOG.lock().unwrap().is_some()
+}
+
+// Standard error has messages for people unless it carries events
+fn plain_stderr() -> bool {
+    EVENT_LOG
+        .lock()
+        .unwrap()
+        .as_ref()
+        .is_none_or(|log| !log.stderr)
+}
 
-        Ok(())
+fn warn(message: &str) {
+    log_event("warning", &[("message", json_string(message))]);
+    if plain_stderr() {
+        eprintln!("WARNING: {message}");
     }
 }
 
-impl Drop for SimpleTarArchive {
-    fn drop(&mut self) {
-        // End of file padding
-        self.writer
-            .write_all(&Self::ZEROS)
-            .expect("Could not write TAR file end-of-file marker");
+fn log_cbt_event(event: CbtEvent) {
+    match event {
+        CbtEvent::Submitted { page, input, name } => log_event(
+            "submitted",
+            &[
+                ("page", page.to_string()),
+                ("input", json_string(&input.to_string_lossy())),
+                ("name", json_string(name)),
+            ],
+        ),
+        CbtEvent::EncoderStarted { page, program, pid } => log_event(
+            "encoder_started",
+            &[
+                ("page", page.to_string()),
+                ("program", json_string(program)),
+                ("pid", pid.to_string()),
+            ],
+        ),
+        CbtEvent::EncoderFinished {
+            page,
+            status,
+            elapsed,
+        } => log_event(
+            "encoder_finished",
+            &[
+                ("page", page.to_string()),
+                ("success", status.success().to_string()),
+                (
+                    "exit_code",
+                    status.code().map_or("null".to_string(), |x| x.to_string()),
+                ),
+                ("seconds", format!("{:.3}", elapsed.as_secs_f64())),
+            ],
+        ),
+        CbtEvent::PageWritten {
+            page,
+            name,
+            offset,
+            size,
+        } => log_event(
+            "page_written",
+            &[
+                ("page", page.to_string()),
+                ("name", json_string(name)),
+                ("offset", offset.to_string()),
+                ("size", size.to_string()),
+            ],
+        ),
+        CbtEvent::Warning(message) => warn(message),
+    }
+}
 
-        // Flush
-        self.writer
-            .flush()
-            .expect("Could not flush TAR file buffer");
+#[cfg(unix)]
+fn event_fd(fd: i32) -> Result<File> {
+    use std::os::fd::FromRawFd;
+    unsafe extern "C" {
+        fn fcntl(fd: i32, cmd: i32, ...) -> i32;
+    }
+    const F_GETFD: i32 = 1;
+    // Only an open descriptor can be owned
+    if unsafe { fcntl(fd, F_GETFD) } == -1 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("File descriptor {fd} is not open"),
+        ));
     }
+    Ok(unsafe { File::from_raw_fd(fd) })
 }
 
-enum CbtWriterJob {
-    Copy(PathBuf, usize),
-    Convert(Child, PathBuf, usize),
+#[cfg(not(unix))]
+fn event_fd(_fd: i32) -> Result<File> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "Option '--json-events-fd' is not supported on this platform",
+    ))
 }
 
-struct CbtWriter {
-    tar: SimpleTarArchive,
-    jobs: VecDeque<CbtWriterJob>,
-    index: usize,
-    padding: usize,
-    processes: usize,
-    work_dir: TempDir,
+fn usage() -> ! {
+    eprintln!("{USAGE}");
+    std::process::exit(1);
 }
 
-impl CbtWriter {
-    fn new(writer: impl Write + 'static, padding: usize) -> Result<Self> {
-        let processes = std::thread::available_parallelism()?.get();
-        Ok(Self {
-            tar: SimpleTarArchive::new(writer),
-            jobs: VecDeque::with_capacity(processes),
-            index: 1,
-            padding,
-            processes,
-            work_dir: TempDir::new("mkcbt"),
-        })
-    }
-
-    fn create<P: AsRef<Path>>(path: P, padding: usize) -> Result<Self> {
-        let processes = std::thread::available_parallelism()?.get();
-        Ok(Self {
-            tar: SimpleTarArchive::create(path)?,
-            jobs: VecDeque::with_capacity(processes),
-            index: 1,
-            padding,
-            processes,
-            work_dir: TempDir::new("mkcbt"),
-        })
-    }
-
-    fn submit(&mut self, path: &Path) -> Result<()> {
-        while self.jobs.len() >= self.processes {
-            let job = self.jobs.pop_front().unwrap();
-            match job {
-                CbtWriterJob::Copy(path, index) => self
-                    .tar
-                    .write_file(path, &format!("{:0fill$}.avif", index, fill = self.padding))?,
-                CbtWriterJob::Convert(mut proc, path, index) => {
-                    if !proc.wait()?.success() {
-                        return Err(Error::new(ErrorKind::Other, "avifenc returned failure"));
+// New archive, or pages added to an existing one
+fn create(args: Vec<String>, append: bool) -> CbtResult<()> {
+    let mut format = None;
+    let mut comic_info = None;
+    let mut preset = None;
+    let mut sort_mode = SortMode::Natural;
+    let mut non_images = NonImagePolicy::Warn;
+    let mut recursive = false;
+    let mut encoder = Encoder::Avif;
+    let mut settings = EncoderSettings::default();
+    let mut on_error = FailurePolicy::Abort;
+    let mut max_ahead = None;
+    let mut jobs = None;
+    let mut progress = ProgressMode::Auto;
+    let mut cache_dir = None;
+    let mut resume = false;
+    let mut state_dir = None;
+    let mut keep_smaller = false;
+    let mut min_savings = 0;
+    let mut threads_per_job = 1;
+    let mut nice = None;
+    let mut idle_io = false;
+    let mut failure_report = None;
+    let mut json_events = None;
+    let mut positional = Vec::new();
+    let mut parser = ArgParser::new(args);
+    while let Some(arg) = parser.next()? {
+        match arg {
+            Arg::Option(name) => match name.as_str() {
+                "--format" => format = Some(ArchiveFormat::parse(&parser.value()?)?),
+                "--sort" => sort_mode = SortMode::parse(&parser.value()?)?,
+                "--sort-regex" => sort_mode = SortMode::regex(&parser.value()?)?,
+                "--recursive" => recursive = true,
+                "--bookmarks" => comic_info.get_or_insert_with(ComicInfo::new).bookmarks = true,
+                "--non-images" => non_images = NonImagePolicy::parse(&parser.value()?)?,
+                "--encoder" => encoder = Encoder::parse(&parser.value()?)?,
+                "--preset" => preset = Some(EncoderSettings::preset(&parser.value()?)?),
+                "--quality" => settings.quality = Some(parser.number(0, 100)?),
+                "--min-quantizer" => settings.min_quantizer = Some(parser.number(0, 63)?),
+                "--max-quantizer" => settings.max_quantizer = Some(parser.number(0, 63)?),
+                "--speed" => settings.speed = Some(parser.number(0, 10)?),
+                "--yuv" => {
+                    let yuv = parser.number(400, 444)?;
+                    if ![400, 420, 422, 444].contains(&yuv) {
+                        return Err(Error::new(
+                            ErrorKind::InvalidInput,
+                            "Option '--yuv' requires one of 444, 422, 420 or 400",
+                        )
+                        .into());
                     }
-                    self.tar.write_file(
-                        &path,
-                        &format!("{:0fill$}.avif", index, fill = self.padding),
-                    )?;
-                    fs::remove_file(path)?;
-                }
-            }
-        }
-        match path.extension() {
-            Some(ext) => {
-                if !ext.eq_ignore_ascii_case("avif") {
-                    let tmp_path = self.work_dir.path().join(format!(
-                        "{:0fill$}.avif",
-                        self.index,
-                        fill = self.padding
-                    ));
-                    self.jobs.push_back(CbtWriterJob::Convert(
-                        Command::new("avifenc")
-                            .args(["--jobs", "1"])
-                            .args(["--speed", "0"])
-                            .arg(path)
-                            .arg(&tmp_path)
-                            .stdout(Stdio::null())
-                            .stderr(Stdio::null())
-                            .spawn()?,
-                        tmp_path,
-                        self.index,
-                    ))
-                } else {
-                    self.jobs
-                        .push_back(CbtWriterJob::Copy(path.to_path_buf(), self.index));
+                    settings.yuv = Some(yuv);
                 }
-            }
-            None => {
-                let tmp_path = self.work_dir.path().join(format!(
-                    "{:0fill$}.avif",
-                    self.index,
-                    fill = self.padding
-                ));
-                self.jobs.push_back(CbtWriterJob::Convert(
-                    Command::new("avifenc")
-                        .args(["--jobs", "1"])
-                        .args(["--speed", "0"])
-                        .arg(path)
-                        .arg(&tmp_path)
-                        .stdout(Stdio::null())
-                        .stderr(Stdio::null())
-                        .spawn()?,
-                    tmp_path,
-                    self.index,
-                ))
-            }
-        }
-        self.index += 1;
-        Ok(())
-    }
-
-    fn finish(&mut self) -> Result<()> {
-        while let Some(job) = self.jobs.pop_front() {
-            match job {
-                CbtWriterJob::Copy(path, index) => self
-                    .tar
-                    .write_file(path, &format!("{:0fill$}.avif", index, fill = self.padding))?,
-                CbtWriterJob::Convert(mut proc, path, index) => {
-                    if !proc.wait()?.success() {
-                        return Err(Error::new(ErrorKind::Other, "avifenc returned failure"));
+                "--depth" => {
+                    let depth = parser.number(8, 12)?;
+                    if ![8, 10, 12].contains(&depth) {
+                        return Err(Error::new(
+                            ErrorKind::InvalidInput,
+                            "Option '--depth' requires one of 8, 10 or 12",
+                        )
+                        .into());
                     }
-                    self.tar.write_file(
-                        &path,
-                        &format!("{:0fill$}.avif", index, fill = self.padding),
-                    )?;
-                    fs::remove_file(path)?;
+                    settings.depth = Some(depth);
                 }
-            }
+                "--lossless" => settings.lossless = true,
+                "--lossless-jpeg" => settings.lossless_jpeg = true,
+                "--jobs" => jobs = Some(parser.number(1, 1024)?),
+                "--threads-per-job" => threads_per_job = parser.number(1, 256)?,
+                "--nice" => nice = Some(parser.number(0, 19)?),
+                "--idle-io" => idle_io = true,
+                "--max-ahead" => max_ahead = Some(parser.number(1, 100_000)?),
+                "--cache" => {
+                    cache_dir.get_or_insert(None);
+                }
+                "--cache-dir" => cache_dir = Some(Some(PathBuf::from(parser.value()?))),
+                "--progress" => progress = ProgressMode::parse(&parser.value()?)?,
+                "--json-events" => json_events = Some(None),
+                "--json-events-fd" => json_events = Some(Some(parser.number(0, i32::MAX)?)),
+                "--resume" => resume = true,
+                "--state-dir" => state_dir = Some(PathBuf::from(parser.value()?)),
+                "--keep-smaller" => keep_smaller = true,
+                "--min-savings" => {
+                    keep_smaller = true;
+                    min_savings = parser.number(0, 99)?;
+                }
+                "--on-error" => on_error = FailurePolicy::parse(&parser.value()?)?,
+                "--keep-going" => on_error = FailurePolicy::Store,
+                "--failure-report" => failure_report = Some(PathBuf::from(parser.value()?)),
+                "--comicinfo" => {
+                    comic_info.get_or_insert_with(ComicInfo::new);
+                }
+                "--metadata" => comic_info
+                    .get_or_insert_with(ComicInfo::new)
+                    .load(parser.value()?)?,
+                "--meta" => {
+                    let value = parser.value()?;
+                    let (field, value) = value.split_once('=').ok_or_else(|| {
+                        Error::new(
+                            ErrorKind::InvalidInput,
+                            "Option '--meta' requires FIELD=VALUE",
+                        )
+                    })?;
+                    comic_info
+                        .get_or_insert_with(ComicInfo::new)
+                        .set(field, value)?;
+                }
+                _ => match ComicInfo::option_field(&name) {
+                    Some(field) => comic_info
+                        .get_or_insert_with(ComicInfo::new)
+                        .set(field, &parser.value()?)?,
+                    None => return Err(parser.unknown().into()),
+                },
+            },
+            Arg::Positional(arg) => positional.push(arg),
         }
-        Ok(())
     }
-}
+    if positional.len() < 2 {
+        usage();
+    }
 
-fn run() -> Result<()> {
-    if env::args().len() < 3 {
-        eprintln!("USAGE: mkcbt OUTPUT.cbt INPUTS...");
-        std::process::exit(1);
+    let output = positional.remove(0);
+    if append && (output == "-" || format.is_some()) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Appending requires an existing CBT file and no '--format'",
+        )
+        .into());
     }
+    if let Some(fd) = json_events {
+        let log = match fd {
+            Some(fd) => EventLog {
+                writer: Box::new(event_fd(fd)?),
+                stderr: false,
+            },
+            None => EventLog {
+                writer: Box::new(std::io::stderr()),
+                stderr: true,
+            },
+        };
+        *EVENT_LOG.lock().unwrap() = Some(log);
+    }
+    let start = Instant::now();
+    catch_signals();
 
-    let cl_inputs: Vec<_> = env::args().skip(2).map(PathBuf::from).collect();
-    let mut inputs = Vec::new();
-    for cl_input in cl_inputs {
-        if !cl_input.exists() {
+    let mut builder = CbtBuilder::new()
+        .inputs(positional)
+        .sort(sort_mode)
+        .non_images(non_images)
+        .recursive(recursive)
+        .encoder(
+            encoder,
+            settings.or(preset.unwrap_or(EncoderSettings::preset("archive")?)),
+        )
+        .failure_policy(on_error)
+        .threads_per_job(threads_per_job)
+        .idle_io(idle_io);
+    if let Some(nice) = nice {
+        builder = builder.nice(nice);
+    }
+    if let Some(format) = format {
+        builder = builder.format(format);
+    }
+    if let Some(jobs) = jobs {
+        builder = builder.jobs(jobs);
+    }
+    if let Some(max_ahead) = max_ahead {
+        builder = builder.max_ahead(max_ahead);
+    }
+    if keep_smaller {
+        builder = builder.keep_smaller(min_savings);
+    }
+    if let Some(dir) = cache_dir {
+        let dir = match dir {
+            Some(dir) => dir,
+            None => EncodeCache::default_dir()?,
+        };
+        builder = builder.cache(EncodeCache::new(dir));
+    }
+
+    // Without --resume an existing state directory is started over
+    if resume && state_dir.is_none() {
+        if output == "-" {
             return Err(Error::new(
-                ErrorKind::NotFound,
-                format!("'{}' does not exist", cl_input.display()),
-            ));
-        }
-        if cl_input.is_dir() {
-            let mut files: Vec<_> = fs::read_dir(cl_input)?
-                .filter_map(|entry| entry.ok().map(|e| e.path()))
-                .filter(|path| path.is_file())
-                .collect();
-            files.sort();
-            inputs.append(&mut files);
-        } else {
-            inputs.push(cl_input);
+                ErrorKind::InvalidInput,
+                "Option '--resume' requires '--state-dir' when writing to standard output",
+            )
+            .into());
         }
+        state_dir = Some(PathBuf::from(format!("{output}.mkcbt-state")));
+    }
+    if let Some(dir) = state_dir {
+        builder = builder.state_dir(dir, resume);
     }
 
-    let output = env::args().nth(1).unwrap();
-    let mut cbt = if output == "-" {
-        CbtWriter::new(std::io::stdout(), inputs.len().ilog10() as usize + 1)?
+    // Progress would be mixed into the events
+    if !plain_stderr() && progress == ProgressMode::Auto {
+        progress = ProgressMode::Off;
+    }
+    match progress.resolve() {
+        ProgressMode::Off => {}
+        mode => builder = builder.progress(mode == ProgressMode::Bar),
+    }
+    if events_enabled() {
+        builder = builder.on_event(log_cbt_event);
+    }
+    if let Some(comic_info) = comic_info {
+        builder = builder.comic_info(comic_info);
+    }
+
+    let summary = if append {
+        builder.append(&output)?
+    } else if output == "-" {
+        builder.write(std::io::stdout())?.1
     } else {
-        CbtWriter::create(output, inputs.len().ilog10() as usize + 1)?
+        builder.create(&output)?
     };
+    let CbtSummary { pages, failures } = summary;
+    let failed: Vec<_> = failures.iter().map(|x| x.page.to_string()).collect();
+    log_event(
+        "summary",
+        &[
+            ("output", json_string(&output)),
+            ("pages", pages.to_string()),
+            ("failed", format!("[{}]", failed.join(","))),
+            ("seconds", format!("{:.3}", start.elapsed().as_secs_f64())),
+        ],
+    );
 
-    for file in inputs {
-        cbt.submit(file.as_path())?;
+    // Partial result still counts as failure
+    if let Some(path) = failure_report {
+        let mut report = String::new();
+        for failure in &failures {
+            report.push_str(&format!("{failure}\n"));
+        }
+        fs::write(path, report)?;
+    }
+    if !failures.is_empty() {
+        if plain_stderr() {
+            eprintln!("WARNING: Pages that could not be converted:");
+            for failure in &failures {
+                eprintln!("  {failure}");
+            }
+        }
+        // Skipped pages were not written
+        let skipped = failures.iter().filter(|x| !x.stored).count();
+        return Err(CbtError::Partial {
+            failed: failures.len(),
+            total: pages + skipped,
+        });
     }
-    cbt.finish()?;
 
     Ok(())
 }
 
+fn list(archive: &str) -> Result<()> {
+    let mut tar = open_archive(archive)?;
+    let mut stdout = std::io::stdout().lock();
+    while let Some(entry) = tar.next_entry()? {
+        writeln!(
+            stdout,
+            "{:>12} {:>12} {}",
+            entry.offset, entry.size, entry.name
+        )?;
+    }
+    stdout.flush()
+}
+
+fn extract(archive: &str, directory: &Path) -> Result<()> {
+    open_archive(archive)?.extract(directory)
+}
+
+fn info(archive: &str) -> Result<()> {
+    let mut tar = open_archive(archive)?;
+    let (mut entries, mut pages, mut directories, mut page_bytes, mut end) = (0, 0, 0, 0, 0);
+    let mut largest: Option<TarEntry> = None;
+    while let Some(entry) = tar.next_entry()? {
+        entries += 1;
+        end = entry.end();
+        if entry.is_dir() {
+            directories += 1;
+        } else if entry.is_file() && ImageFormat::from_name(&entry.name).is_some() {
+            pages += 1;
+            page_bytes += entry.size;
+            if largest.as_ref().is_none_or(|x| entry.size > x.size) {
+                largest = Some(entry);
+            }
+        }
+    }
+
+    let mut stdout = std::io::stdout().lock();
+    writeln!(stdout, "Archive:      {archive}")?;
+    writeln!(stdout, "Entries:      {entries}")?;
+    writeln!(stdout, "Pages:        {pages}")?;
+    writeln!(stdout, "Other files:  {}", entries - pages - directories)?;
+    writeln!(stdout, "Directories:  {directories}")?;
+    writeln!(stdout, "Page bytes:   {page_bytes}")?;
+    if let Some(largest) = largest {
+        writeln!(
+            stdout,
+            "Largest page: {} ({} bytes)",
+            largest.name, largest.size
+        )?;
+    }
+    writeln!(stdout, "Data end:     {end}")?;
+    stdout.flush()
+}
+
+fn cache(args: Vec<String>) -> Result<()> {
+    let mut dir = None;
+    let mut max_size = None;
+    let mut max_age = None;
+    let mut positional = Vec::new();
+    let mut parser = ArgParser::new(args);
+    while let Some(arg) = parser.next()? {
+        match arg {
+            Arg::Option(name) => match name.as_str() {
+                "--cache-dir" => dir = Some(PathBuf::from(parser.value()?)),
+                "--max-size" => max_size = Some(parser.size()?),
+                "--max-age" => max_age = Some(parser.number(0u64, 100_000)?),
+                _ => return Err(parser.unknown()),
+            },
+            Arg::Positional(arg) => positional.push(arg),
+        }
+    }
+    let cache = EncodeCache::new(match dir {
+        Some(dir) => dir,
+        None => EncodeCache::default_dir()?,
+    });
+    let entries = cache.entries()?;
+
+    let mut stdout = std::io::stdout().lock();
+    let (mut removed, mut removed_bytes) = (0, 0);
+    match positional.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        ["list"] => {
+            let now = SystemTime::now();
+            for entry in &entries {
+                let age = now.duration_since(entry.modified).unwrap_or_default();
+                writeln!(
+                    stdout,
+                    "{:>12} {:>5}d {}",
+                    entry.size,
+                    age.as_secs() / 86400,
+                    entry.path.file_name().unwrap_or_default().to_string_lossy()
+                )?;
+            }
+            let total: u64 = entries.iter().map(|entry| entry.size).sum();
+            writeln!(stdout, "{} pages, {total} bytes", entries.len())?;
+            return stdout.flush();
+        }
+        ["prune"] if max_size.is_some() || max_age.is_some() => {
+            let cutoff = max_age.map(|days| {
+                SystemTime::now()
+                    .checked_sub(Duration::from_secs(days * 86400))
+                    .unwrap_or(UNIX_EPOCH)
+            });
+            let mut total: u64 = entries.iter().map(|entry| entry.size).sum();
+            for entry in &entries {
+                let expired = cutoff.is_some_and(|cutoff| entry.modified < cutoff);
+                if expired || max_size.is_some_and(|max_size| total > max_size) {
+                    cache.remove(entry)?;
+                    total -= entry.size;
+                    removed += 1;
+                    removed_bytes += entry.size;
+                }
+            }
+        }
+        ["prune"] => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Pruning requires '--max-size' or '--max-age'",
+            ));
+        }
+        ["clear"] => {
+            for entry in &entries {
+                cache.remove(entry)?;
+                removed += 1;
+                removed_bytes += entry.size;
+            }
+        }
+        _ => usage(),
+    }
+    writeln!(stdout, "Removed {removed} pages, {removed_bytes} bytes")?;
+    stdout.flush()
+}
+
+fn run() -> CbtResult<()> {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    match (args.first().map(String::as_str), args.len()) {
+        (Some("list"), 2) => Ok(list(&args[1])?),
+        (Some("info"), 2) => Ok(info(&args[1])?),
+        (Some("extract"), 2 | 3) => Ok(extract(
+            &args[1],
+            Path::new(args.get(2).map(String::as_str).unwrap_or(".")),
+        )?),
+        (Some("list" | "info" | "extract"), _) => usage(),
+        (Some("cache"), _) => Ok(cache(args.split_off(1))?),
+        (Some("create"), _) => create(args.split_off(1), false),
+        (Some("append"), _) => create(args.split_off(1), true),
+        _ => create(args, false),
+    }
+}
+
 fn main() -> ExitCode {
     match run() {
         Ok(()) => ExitCode::SUCCESS,
+        // Reader went away, as with `mkcbt list ARCHIVE | head`
+        Err(CbtError::Io(err)) if err.kind() == ErrorKind::BrokenPipe => ExitCode::from(141),
         Err(err) => {
-            eprintln!("ERROR: {err}");
-            ExitCode::FAILURE
+            log_event(
+                "error",
+                &[
+                    ("message", json_string(&err.to_string())),
+                    ("exit_code", err.exit_code().to_string()),
+                ],
+            );
+            if plain_stderr() {
+                eprintln!("ERROR: {err}");
+            }
+            ExitCode::from(err.exit_code())
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_escaping() {
+        assert_eq!(json_string(""), "\"\"");
+        assert_eq!(json_string("Ch 1/01.avif"), "\"Ch 1/01.avif\"");
+        assert_eq!(json_string("say \"hi\"\\now"), "\"say \\\"hi\\\"\\\\now\"");
+        assert_eq!(json_string("a\nb\r\tc"), "\"a\\nb\\r\\tc\"");
+        assert_eq!(json_string("\0\x1b\x7f"), "\"\\u0000\\u001b\x7f\"");
+        assert_eq!(json_string("ü 🙂"), "\"ü 🙂\"");
+    }
+}
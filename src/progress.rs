@@ -0,0 +1,200 @@
+//
+// Copyright 2024-2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+use std::time::{Duration, Instant};
+
+// Pages written so far, with sizes, compression ratio and estimated time left
+pub(crate) struct Progress {
+    bar: bool,
+    total: usize,
+    done: usize,
+    bytes_in: u64,
+    bytes_out: u64,
+    start: Instant,
+    drawn: Option<Instant>,
+}
+
+impl Progress {
+    const REDRAW: Duration = Duration::from_millis(100);
+
+    pub(crate) fn new(total: usize, bar: bool) -> Self {
+        Self {
+            bar,
+            total,
+            done: 0,
+            bytes_in: 0,
+            bytes_out: 0,
+            start: Instant::now(),
+            drawn: None,
+        }
+    }
+
+    // Page written (or skipped, with nothing out)
+    pub(crate) fn page(&mut self, name: &str, bytes_in: u64, bytes_out: u64) {
+        self.done += 1;
+        self.bytes_in += bytes_in;
+        self.bytes_out += bytes_out;
+        if !self.bar {
+            eprintln!(
+                "{} {name} {} -> {}, {}",
+                self.count(),
+                format_bytes(bytes_in),
+                format_bytes(bytes_out),
+                self.status()
+            );
+        } else if self.drawn.is_none_or(|x| x.elapsed() >= Self::REDRAW) || self.done == self.total
+        {
+            eprint!("\r\x1b[K{} {}", self.count(), self.status());
+            self.drawn = Some(Instant::now());
+        }
+    }
+
+    fn count(&self) -> String {
+        let width = self.total.to_string().len();
+        format!("[{:>width$}/{}]", self.done, self.total)
+    }
+
+    fn status(&self) -> String {
+        let ratio = match self.bytes_in {
+            0 => 100.0,
+            bytes_in => self.bytes_out as f64 * 100.0 / bytes_in as f64,
+        };
+        let eta = self
+            .eta(self.start.elapsed())
+            .map_or("--:--".to_string(), format_duration);
+        format!(
+            "{} -> {} ({ratio:.1}%) ETA {eta}",
+            format_bytes(self.bytes_in),
+            format_bytes(self.bytes_out)
+        )
+    }
+
+    // Remaining pages at the average pace so far
+    fn eta(&self, elapsed: Duration) -> Option<Duration> {
+        match self.done {
+            0 => None,
+            done => Some(elapsed * (self.total - done.min(self.total)) as u32 / done as u32),
+        }
+    }
+
+    // Before other messages, so they do not run into the status line
+    pub(crate) fn clear(&self) {
+        if self.bar && self.drawn.is_some() {
+            eprint!("\r\x1b[K");
+        }
+    }
+
+    pub(crate) fn finish(&mut self) {
+        self.clear();
+        self.drawn = None;
+        eprintln!(
+            "Done: {} pages, {} -> {} in {}",
+            self.done,
+            format_bytes(self.bytes_in),
+            format_bytes(self.bytes_out),
+            format_duration(self.start.elapsed())
+        );
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+// H:MM:SS, or M:SS under an hour
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs / 3600 {
+        0 => format!("{}:{:02}", secs / 60, secs % 60),
+        hours => format!("{hours}:{:02}:{:02}", secs / 60 % 60, secs % 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1024), "1.0 KiB");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(5 << 20), "5.0 MiB");
+        assert_eq!(format_bytes(3 << 30), "3.0 GiB");
+        assert_eq!(format_bytes(2048 << 40), "2048.0 TiB");
+    }
+
+    #[test]
+    fn durations() {
+        let secs = Duration::from_secs;
+        assert_eq!(format_duration(secs(0)), "0:00");
+        assert_eq!(format_duration(Duration::from_millis(59_999)), "0:59");
+        assert_eq!(format_duration(secs(61)), "1:01");
+        assert_eq!(format_duration(secs(3599)), "59:59");
+        assert_eq!(format_duration(secs(3600)), "1:00:00");
+        assert_eq!(format_duration(secs(90061)), "25:01:01");
+    }
+
+    #[test]
+    fn eta() {
+        let mut progress = Progress::new(10, true);
+        assert_eq!(progress.eta(Duration::from_secs(5)), None);
+        progress.done = 4;
+        assert_eq!(
+            progress.eta(Duration::from_secs(8)),
+            Some(Duration::from_secs(12))
+        );
+        // Pages beyond the total leave nothing
+        for done in [10, 12] {
+            progress.done = done;
+            assert_eq!(progress.eta(Duration::from_secs(8)), Some(Duration::ZERO));
+        }
+    }
+
+    #[test]
+    fn status_line() {
+        let mut progress = Progress::new(100, true);
+        progress.bytes_in = 2048;
+        progress.bytes_out = 512;
+        assert_eq!(progress.count(), "[  0/100]");
+        assert_eq!(progress.status(), "2.0 KiB -> 512 B (25.0%) ETA --:--");
+        progress.bytes_in = 0;
+        assert!(progress.status().contains("(100.0%)"));
+    }
+}
@@ -0,0 +1,61 @@
+//
+// Copyright 2024-2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+use std::fs::File;
+use std::io::{BufReader, Read, Result, Write};
+use std::path::Path;
+
+use crate::tar::{SimpleTarReader, TarEntry};
+
+/// Entries of a CBT archive, read in order from a stream
+#[derive(Debug)]
+pub struct CbtReader<R: Read> {
+    tar: SimpleTarReader<R>,
+}
+
+impl CbtReader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self::new(BufReader::new(File::open(path)?)))
+    }
+}
+
+impl<R: Read> CbtReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            tar: SimpleTarReader::new(reader),
+        }
+    }
+
+    pub fn next_entry(&mut self) -> Result<Option<TarEntry>> {
+        self.tar.next_entry()
+    }
+
+    /// Data of the entry last returned by next_entry
+    pub fn copy_data(&mut self, entry: &TarEntry, writer: &mut impl Write) -> Result<()> {
+        self.tar.copy_data(entry, writer)
+    }
+
+    /// Unpack the remaining entries under the given directory
+    pub fn extract<P: AsRef<Path>>(&mut self, directory: P) -> Result<()> {
+        self.tar.extract(directory)
+    }
+}
@@ -0,0 +1,494 @@
+//
+// Copyright 2024-2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+use std::io::{Error, ErrorKind, Result};
+
+// Minimal backtracking regular expressions for --sort-regex
+enum ClassItem {
+    Range(char, char),
+    Digit(bool),
+    Word(bool),
+    Space(bool),
+}
+
+impl ClassItem {
+    fn matches(&self, c: char) -> bool {
+        match *self {
+            Self::Range(first, last) => (first..=last).contains(&c),
+            Self::Digit(negated) => c.is_ascii_digit() != negated,
+            Self::Word(negated) => (c.is_alphanumeric() || c == '_') != negated,
+            Self::Space(negated) => c.is_whitespace() != negated,
+        }
+    }
+}
+
+enum RegexNode {
+    Any,
+    Class(Vec<ClassItem>, bool),
+    Start,
+    End,
+    Group(Box<RegexNode>, Option<usize>),
+    Concat(Vec<RegexNode>),
+    Alternate(Vec<RegexNode>),
+    Repeat(Box<RegexNode>, usize, Option<usize>, bool),
+}
+
+type Captures = Vec<Option<(usize, usize)>>;
+
+pub(crate) struct Regex {
+    root: RegexNode,
+    groups: usize,
+}
+
+impl Regex {
+    pub(crate) fn new(pattern: &str) -> Result<Self> {
+        let chars: Vec<char> = pattern.chars().collect();
+        let mut parser = RegexParser {
+            chars: &chars,
+            position: 0,
+            groups: 0,
+        };
+        let root = parser.alternate()?;
+        if parser.position < chars.len() {
+            return Err(parser.error("unmatched ')'"));
+        }
+        Ok(Self {
+            root,
+            groups: parser.groups,
+        })
+    }
+
+    // Whole match followed by each capture group of the leftmost match
+    pub(crate) fn captures(&self, text: &str) -> Option<Vec<Option<String>>> {
+        let chars: Vec<char> = text.chars().collect();
+        for start in 0..=chars.len() {
+            let mut captures = vec![None; self.groups + 1];
+            let mut end = None;
+            if Self::match_node(&self.root, &chars, start, &mut captures, &mut |x, _| {
+                end = Some(x);
+                true
+            }) {
+                captures[0] = Some((start, end.unwrap()));
+                return Some(
+                    captures
+                        .into_iter()
+                        .map(|x| x.map(|(a, b)| chars[a..b].iter().collect()))
+                        .collect(),
+                );
+            }
+        }
+        None
+    }
+
+    fn match_node(
+        node: &RegexNode,
+        text: &[char],
+        pos: usize,
+        captures: &mut Captures,
+        next: &mut dyn FnMut(usize, &mut Captures) -> bool,
+    ) -> bool {
+        match node {
+            RegexNode::Any => pos < text.len() && next(pos + 1, captures),
+            RegexNode::Class(items, negated) => {
+                pos < text.len()
+                    && items.iter().any(|item| item.matches(text[pos])) != *negated
+                    && next(pos + 1, captures)
+            }
+            RegexNode::Start => pos == 0 && next(pos, captures),
+            RegexNode::End => pos == text.len() && next(pos, captures),
+            RegexNode::Group(inner, index) => {
+                Self::match_node(inner, text, pos, captures, &mut |end, captures| {
+                    let Some(index) = *index else {
+                        return next(end, captures);
+                    };
+                    let old = captures[index];
+                    captures[index] = Some((pos, end));
+                    if next(end, captures) {
+                        return true;
+                    }
+                    captures[index] = old;
+                    false
+                })
+            }
+            RegexNode::Concat(nodes) => Self::match_sequence(nodes, text, pos, captures, next),
+            RegexNode::Alternate(nodes) => nodes
+                .iter()
+                .any(|node| Self::match_node(node, text, pos, captures, next)),
+            RegexNode::Repeat(inner, min, max, greedy) => {
+                Self::match_repeat(inner, (*min, *max, *greedy), 0, text, pos, captures, next)
+            }
+        }
+    }
+
+    fn match_sequence(
+        nodes: &[RegexNode],
+        text: &[char],
+        pos: usize,
+        captures: &mut Captures,
+        next: &mut dyn FnMut(usize, &mut Captures) -> bool,
+    ) -> bool {
+        match nodes.split_first() {
+            None => next(pos, captures),
+            Some((first, rest)) => {
+                Self::match_node(first, text, pos, captures, &mut |x, captures| {
+                    Self::match_sequence(rest, text, x, captures, next)
+                })
+            }
+        }
+    }
+
+    fn match_repeat(
+        node: &RegexNode,
+        (min, max, greedy): (usize, Option<usize>, bool),
+        count: usize,
+        text: &[char],
+        pos: usize,
+        captures: &mut Captures,
+        next: &mut dyn FnMut(usize, &mut Captures) -> bool,
+    ) -> bool {
+        let more = |captures: &mut Captures, next: &mut dyn FnMut(usize, &mut Captures) -> bool| {
+            max.is_none_or(|max| count < max)
+                && Self::match_node(node, text, pos, captures, &mut |x, captures| {
+                    // Empty iterations past the minimum would never end
+                    (x != pos || count < min)
+                        && Self::match_repeat(
+                            node,
+                            (min, max, greedy),
+                            count + 1,
+                            text,
+                            x,
+                            captures,
+                            next,
+                        )
+                })
+        };
+        if greedy && more(captures, next) {
+            return true;
+        }
+        if count >= min && next(pos, captures) {
+            return true;
+        }
+        !greedy && more(captures, next)
+    }
+}
+
+struct RegexParser<'a> {
+    chars: &'a [char],
+    position: usize,
+    groups: usize,
+}
+
+impl RegexParser<'_> {
+    fn error(&self, message: &str) -> Error {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Bad regular expression at position {}: {message}",
+                self.position
+            ),
+        )
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn alternate(&mut self) -> Result<RegexNode> {
+        let mut alternatives = vec![self.concat()?];
+        while self.eat('|') {
+            alternatives.push(self.concat()?);
+        }
+        Ok(if alternatives.len() == 1 {
+            alternatives.pop().unwrap()
+        } else {
+            RegexNode::Alternate(alternatives)
+        })
+    }
+
+    fn concat(&mut self) -> Result<RegexNode> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.atom()?;
+            nodes.push(self.quantifier(atom)?);
+        }
+        Ok(RegexNode::Concat(nodes))
+    }
+
+    fn quantifier(&mut self, atom: RegexNode) -> Result<RegexNode> {
+        let (min, max) = match self.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => {
+                let start = self.position;
+                self.position += 1;
+                let number = |parser: &mut Self| {
+                    let begin = parser.position;
+                    while parser.peek().is_some_and(|c| c.is_ascii_digit()) {
+                        parser.position += 1;
+                    }
+                    parser.chars[begin..parser.position]
+                        .iter()
+                        .collect::<String>()
+                        .parse::<usize>()
+                        .ok()
+                };
+                let min = number(self);
+                let max = if self.eat(',') { number(self) } else { min };
+                if min.is_none() || self.peek() != Some('}') {
+                    // Not a repetition, so a literal brace
+                    self.position = start;
+                    return Ok(atom);
+                }
+                if max.is_some_and(|max| max < min.unwrap()) {
+                    return Err(self.error("bad repetition range"));
+                }
+                (min.unwrap(), max)
+            }
+            _ => return Ok(atom),
+        };
+        self.position += 1;
+        let greedy = !self.eat('?');
+        if matches!(atom, RegexNode::Start | RegexNode::End) {
+            return Err(self.error("nothing to repeat"));
+        }
+        Ok(RegexNode::Repeat(Box::new(atom), min, max, greedy))
+    }
+
+    fn atom(&mut self) -> Result<RegexNode> {
+        let c = self.peek().unwrap();
+        self.position += 1;
+        match c {
+            '(' => {
+                let index = if self.eat('?') {
+                    if !self.eat(':') {
+                        return Err(self.error("unsupported group type"));
+                    }
+                    None
+                } else {
+                    self.groups += 1;
+                    Some(self.groups)
+                };
+                let inner = self.alternate()?;
+                if !self.eat(')') {
+                    return Err(self.error("missing ')'"));
+                }
+                Ok(RegexNode::Group(Box::new(inner), index))
+            }
+            '[' => self.class(),
+            '.' => Ok(RegexNode::Any),
+            '^' => Ok(RegexNode::Start),
+            '$' => Ok(RegexNode::End),
+            '\\' => Ok(RegexNode::Class(vec![self.escape()?], false)),
+            '*' | '+' | '?' => Err(self.error("nothing to repeat")),
+            _ => Ok(RegexNode::Class(vec![ClassItem::Range(c, c)], false)),
+        }
+    }
+
+    fn escape(&mut self) -> Result<ClassItem> {
+        let c = self
+            .peek()
+            .ok_or_else(|| self.error("trailing backslash"))?;
+        self.position += 1;
+        Ok(match c {
+            'd' | 'D' => ClassItem::Digit(c == 'D'),
+            'w' | 'W' => ClassItem::Word(c == 'W'),
+            's' | 'S' => ClassItem::Space(c == 'S'),
+            't' => ClassItem::Range('\t', '\t'),
+            'n' => ClassItem::Range('\n', '\n'),
+            c if c.is_ascii_alphanumeric() => return Err(self.error("unsupported escape")),
+            c => ClassItem::Range(c, c),
+        })
+    }
+
+    fn class(&mut self) -> Result<RegexNode> {
+        let negated = self.eat('^');
+        let mut items = Vec::new();
+        let mut first = true;
+        loop {
+            let c = self.peek().ok_or_else(|| self.error("missing ']'"))?;
+            self.position += 1;
+            if c == ']' && !first {
+                break;
+            }
+            first = false;
+            let item = if c == '\\' {
+                self.escape()?
+            } else {
+                ClassItem::Range(c, c)
+            };
+            // Ranges such as a-z, but not a trailing '-'
+            if let ClassItem::Range(low, _) = item
+                && self.peek() == Some('-')
+                && self.chars.get(self.position + 1) != Some(&']')
+            {
+                self.position += 1;
+                let high = self.peek().ok_or_else(|| self.error("missing ']'"))?;
+                self.position += 1;
+                if high < low {
+                    return Err(self.error("bad character range"));
+                }
+                items.push(ClassItem::Range(low, high));
+                continue;
+            }
+            items.push(item);
+        }
+        Ok(RegexNode::Class(items, negated))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn captures(pattern: &str, text: &str) -> Option<Vec<Option<String>>> {
+        Regex::new(pattern).unwrap().captures(text)
+    }
+
+    fn find(pattern: &str, text: &str) -> Option<String> {
+        captures(pattern, text).map(|x| x[0].clone().unwrap())
+    }
+
+    #[test]
+    fn matches() {
+        for (pattern, text, expected) in [
+            ("abc", "xabcx", Some("abc")),
+            ("abc", "ab", None),
+            ("a.c", "a-c", Some("a-c")),
+            ("^b", "ab", None),
+            ("b$", "ab", Some("b")),
+            ("^$", "", Some("")),
+            ("cat|dog", "hotdog", Some("dog")),
+            // Classes and ranges
+            ("[abc]+", "xxbcay", Some("bca")),
+            ("[^abc]+", "abxyc", Some("xy")),
+            ("[a-c0-2]+", "dd1a2cz", Some("1a2c")),
+            ("[a-]+", "x-a-", Some("-a-")),
+            ("[]a]+", "x]a]", Some("]a]")),
+            ("[\\d.]+", "v1.25x", Some("1.25")),
+            ("\\d+", "page 012", Some("012")),
+            ("\\D+", "12ab3", Some("ab")),
+            ("\\w+", "-ab_9-", Some("ab_9")),
+            ("\\W", "ab cd", Some(" ")),
+            ("\\s+\\S", "a \tb", Some(" \tb")),
+            ("\\.png", "a.png", Some(".png")),
+            ("\\(\\d\\)", "p(3)", Some("(3)")),
+            ("[à-ü]+", "caféx", Some("é")),
+            // Repetition
+            ("ab*", "abbbc", Some("abbb")),
+            ("ab+", "ac", None),
+            ("ab?c", "ac", Some("ac")),
+            ("a{2}", "aaaa", Some("aa")),
+            ("a{2,}", "aaaa", Some("aaaa")),
+            ("a{1,3}", "aaaa", Some("aaa")),
+            ("a{0,1}b", "aab", Some("ab")),
+            ("x{3}", "xx", None),
+            ("a{,2}", "a{,2}", Some("a{,2}")),
+            ("a{x}", "a{x}", Some("a{x}")),
+            ("(?:ab)+", "ababa", Some("abab")),
+            ("(a*)*b", "aab", Some("aab")),
+            // Lazy quantifiers
+            ("a+?", "aaa", Some("a")),
+            ("a*?b", "aab", Some("aab")),
+            ("<.*?>", "<a><b>", Some("<a>")),
+            ("<.*>", "<a><b>", Some("<a><b>")),
+            ("a{2,3}?", "aaaa", Some("aa")),
+            ("a??b", "ab", Some("ab")),
+        ] {
+            assert_eq!(
+                find(pattern, text).as_deref(),
+                expected,
+                "{pattern} on {text}"
+            );
+        }
+    }
+
+    #[test]
+    fn errors() {
+        for (pattern, message) in [
+            ("(ab", "position 3: missing ')'"),
+            ("ab)", "position 2: unmatched ')'"),
+            ("[ab", "position 3: missing ']'"),
+            ("[z-a]", "position 4: bad character range"),
+            ("a{3,2}", "position 5: bad repetition range"),
+            ("*a", "position 1: nothing to repeat"),
+            ("a|+", "position 3: nothing to repeat"),
+            ("^*", "position 2: nothing to repeat"),
+            ("(?=a)", "position 2: unsupported group type"),
+            ("\\k", "position 2: unsupported escape"),
+            ("a\\", "position 2: trailing backslash"),
+        ] {
+            let error = Regex::new(pattern).err().unwrap();
+            assert_eq!(error.kind(), ErrorKind::InvalidInput);
+            assert_eq!(
+                error.to_string(),
+                format!("Bad regular expression at {message}"),
+                "{pattern}"
+            );
+        }
+    }
+
+    #[test]
+    fn groups() {
+        let owned = |x: &[Option<&str>]| x.iter().map(|x| x.map(str::to_string)).collect();
+        assert_eq!(
+            captures("(\\d+)-(\\d+)", "p 12-34 x"),
+            Some(owned(&[Some("12-34"), Some("12"), Some("34")]))
+        );
+        // Groups are numbered by their opening parenthesis
+        assert_eq!(
+            captures("((a)(b))", "ab"),
+            Some(owned(&[Some("ab"), Some("ab"), Some("a"), Some("b")]))
+        );
+        // Non-capturing groups are not counted
+        assert_eq!(
+            captures("(?:x(\\d))+", "x1x2"),
+            Some(owned(&[Some("x1x2"), Some("2")]))
+        );
+        // Groups outside the matching alternative are unset
+        assert_eq!(
+            captures("(a)|(b)", "b"),
+            Some(owned(&[Some("b"), None, Some("b")]))
+        );
+        assert_eq!(captures("(a)?b", "b"), Some(owned(&[Some("b"), None])));
+        // A failed branch does not leave its capture behind
+        assert_eq!(captures("(a)c|ab", "ab"), Some(owned(&[Some("ab"), None])));
+        // Leftmost match wins over a longer later one
+        assert_eq!(
+            captures("(\\d+)", "ch1 p100"),
+            Some(owned(&[Some("1"), Some("1")]))
+        );
+        assert_eq!(captures("(\\d+)", "none"), None);
+    }
+}
@@ -0,0 +1,66 @@
+//
+// Copyright 2024-2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+use std::sync::atomic::{AtomicI32, Ordering as AtomicOrdering};
+
+use crate::error::{CbtError, CbtResult};
+
+// Interrupts are only noted here and acted on between steps, so that encoders
+// are stopped and temporary files removed as everything is dropped
+pub(crate) static SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+/// Interrupts stop the build between steps, and a second one stops mkcbt at
+/// once
+#[cfg(unix)]
+pub fn catch_signals() {
+    const SIGINT: i32 = 2;
+    const SIGTERM: i32 = 15;
+    const SIG_DFL: usize = 0;
+    unsafe extern "C" {
+        fn signal(signum: i32, handler: usize) -> usize;
+    }
+    // Default handling is restored for the next signal, in case the cleanup
+    // hangs
+    extern "C" fn handler(signum: i32) {
+        SIGNAL.store(signum, AtomicOrdering::SeqCst);
+        for signum in [SIGINT, SIGTERM] {
+            unsafe {
+                signal(signum, SIG_DFL);
+            }
+        }
+    }
+    for signum in [SIGINT, SIGTERM] {
+        unsafe {
+            signal(signum, handler as extern "C" fn(i32) as usize);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn catch_signals() {}
+
+pub(crate) fn check_signal() -> CbtResult<()> {
+    match SIGNAL.load(AtomicOrdering::SeqCst) {
+        0 => Ok(()),
+        signum => Err(CbtError::Interrupted(signum)),
+    }
+}
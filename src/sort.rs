@@ -0,0 +1,336 @@
+//
+// Copyright 2024-2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+use std::cmp::Ordering;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+use crate::regex::Regex;
+
+/// Page ordering
+#[derive(Debug, Default)]
+pub enum SortMode {
+    #[default]
+    Natural,
+    Name,
+    Mtime,
+    Exif,
+    /// Pattern checked by SortMode::regex
+    Regex(String),
+}
+
+impl SortMode {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "natural" => Ok(Self::Natural),
+            "name" => Ok(Self::Name),
+            "mtime" => Ok(Self::Mtime),
+            "exif" => Ok(Self::Exif),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unknown sort mode '{name}' (expected one of: natural, name, mtime, exif)"),
+            )),
+        }
+    }
+
+    /// Pages ordered by the number in the first capture group, or the whole match
+    pub fn regex(pattern: &str) -> Result<Self> {
+        Regex::new(pattern)?;
+        Ok(Self::Regex(pattern.to_string()))
+    }
+}
+
+pub(crate) fn sort_pages<T: AsRef<Path> + Clone>(files: &mut [T], mode: &SortMode) -> Result<()> {
+    match mode {
+        SortMode::Natural => sort_keyed(files, vec![(); files.len()]),
+        SortMode::Name => files.sort_by(|a, b| a.as_ref().cmp(b.as_ref())),
+        SortMode::Mtime => {
+            let mut keys = Vec::with_capacity(files.len());
+            for file in files.iter() {
+                keys.push(file.as_ref().metadata()?.modified()?);
+            }
+            sort_keyed(files, keys);
+        }
+        // Pages without a capture time go last
+        SortMode::Exif => {
+            let keys = files
+                .iter()
+                .map(|file| {
+                    let time = exif_date_time(file.as_ref());
+                    (time.is_none(), time)
+                })
+                .collect();
+            sort_keyed(files, keys);
+        }
+        // First capture group (or the whole match) is the page number
+        SortMode::Regex(pattern) => {
+            let regex = Regex::new(pattern)?;
+            let mut keys = Vec::with_capacity(files.len());
+            for file in files.iter() {
+                let name = page_file_name(file.as_ref());
+                let number = regex
+                    .captures(&name)
+                    .and_then(|captures| captures[captures.len().min(2) - 1].clone())
+                    .and_then(|text| text.parse::<u64>().ok())
+                    .ok_or_else(|| {
+                        Error::new(
+                            ErrorKind::InvalidInput,
+                            format!("No page number in '{name}' for --sort-regex"),
+                        )
+                    })?;
+                keys.push(number);
+            }
+            sort_keyed(files, keys);
+        }
+    }
+    Ok(())
+}
+
+// Sort by the given keys, then naturally by file name
+fn sort_keyed<T: AsRef<Path> + Clone, K: Ord>(files: &mut [T], keys: Vec<K>) {
+    let mut keyed: Vec<_> = keys.into_iter().zip(files.iter().cloned()).collect();
+    keyed.sort_by(|(a_key, a), (b_key, b)| {
+        a_key
+            .cmp(b_key)
+            .then_with(|| natural_cmp(&page_file_name(a.as_ref()), &page_file_name(b.as_ref())))
+    });
+    for (file, (_, sorted)) in files.iter_mut().zip(keyed) {
+        *file = sorted;
+    }
+}
+
+pub(crate) fn page_file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+// Compare runs of digits by value and everything else case-insensitively
+pub(crate) fn natural_cmp(a: &str, b: &str) -> Ordering {
+    fn chunks(text: &str) -> Vec<&str> {
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        for (i, c) in text.char_indices().skip(1) {
+            let prev = text[..i].chars().next_back().unwrap();
+            if c.is_ascii_digit() != prev.is_ascii_digit() {
+                chunks.push(&text[start..i]);
+                start = i;
+            }
+        }
+        if start < text.len() {
+            chunks.push(&text[start..]);
+        }
+        chunks
+    }
+
+    for (a_chunk, b_chunk) in chunks(a).into_iter().zip(chunks(b)) {
+        let digits = |x: &str| x.starts_with(|c: char| c.is_ascii_digit());
+        let ordering = if digits(a_chunk) && digits(b_chunk) {
+            let a_value = a_chunk.trim_start_matches('0');
+            let b_value = b_chunk.trim_start_matches('0');
+            a_value
+                .len()
+                .cmp(&b_value.len())
+                .then_with(|| a_value.cmp(b_value))
+        } else {
+            a_chunk
+                .chars()
+                .flat_map(char::to_lowercase)
+                .cmp(b_chunk.chars().flat_map(char::to_lowercase))
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    chunks(a).len().cmp(&chunks(b).len()).then_with(|| a.cmp(b))
+}
+
+// DateTimeOriginal (or DateTime) from the EXIF data of JPEG and TIFF files
+fn exif_date_time(path: &Path) -> Option<String> {
+    let data = fs::read(path).ok()?;
+    let tiff = if data.starts_with(b"\xff\xd8") {
+        let mut i = 2;
+        loop {
+            let marker = *data.get(i + 1)?;
+            let len = u16::from_be_bytes(data.get(i + 2..i + 4)?.try_into().ok()?) as usize;
+            if data[i] != 0xff || marker == 0xda {
+                return None;
+            }
+            let segment = data.get(i + 4..i + 2 + len)?;
+            if marker == 0xe1 && segment.starts_with(b"Exif\0\0") {
+                break &segment[6..];
+            }
+            i += 2 + len;
+        }
+    } else {
+        &data[..]
+    };
+
+    let little_endian = match tiff.get(..4)? {
+        b"II*\0" => true,
+        b"MM\0*" => false,
+        _ => return None,
+    };
+    let u16_at = |i: usize| -> Option<u16> {
+        let bytes = tiff.get(i..i + 2)?.try_into().ok()?;
+        Some(if little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    };
+    let u32_at = |i: usize| -> Option<u32> {
+        let bytes = tiff.get(i..i + 4)?.try_into().ok()?;
+        Some(if little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    };
+    // Value (or offset to it) of a tag in the IFD at the given offset
+    let find_tag = |ifd: usize, tag: u16| -> Option<(u32, usize)> {
+        let count = u16_at(ifd)? as usize;
+        (0..count)
+            .map(|i| ifd + 2 + i * 12)
+            .find(|&entry| u16_at(entry) == Some(tag))
+            .and_then(|entry| Some((u32_at(entry + 4)?, entry + 8)))
+    };
+    let ascii = |(count, entry): (u32, usize)| -> Option<String> {
+        let offset = if count <= 4 {
+            entry
+        } else {
+            u32_at(entry)? as usize
+        };
+        let bytes = tiff.get(offset..offset + count as usize)?;
+        let text = String::from_utf8_lossy(bytes);
+        Some(text.trim_end_matches('\0').trim().to_string()).filter(|x| !x.is_empty())
+    };
+
+    let ifd0 = u32_at(4)? as usize;
+    find_tag(ifd0, 0x8769)
+        .and_then(|(_, entry)| u32_at(entry))
+        .and_then(|exif_ifd| {
+            find_tag(exif_ifd as usize, 0x9003)
+                .or_else(|| find_tag(exif_ifd as usize, 0x9004))
+                .and_then(ascii)
+        })
+        .or_else(|| find_tag(ifd0, 0x0132).and_then(ascii))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn sorted(names: &[&str]) -> Vec<String> {
+        let mut names: Vec<String> = names.iter().map(|x| x.to_string()).collect();
+        names.sort_by(|a, b| natural_cmp(a, b));
+        names
+    }
+
+    #[test]
+    fn natural_numbers() {
+        assert_eq!(
+            sorted(&["page10.png", "page2.png", "page1.png", "page02b.png"]),
+            ["page1.png", "page2.png", "page02b.png", "page10.png"]
+        );
+        assert_eq!(
+            sorted(&["v2c10", "v10c1", "v2c9"]),
+            ["v2c9", "v2c10", "v10c1"]
+        );
+        // Longer than any integer type
+        assert_eq!(
+            natural_cmp("p99999999999999999999999", "p100000000000000000000000"),
+            Ordering::Less
+        );
+    }
+
+    #[test]
+    fn natural_leading_zeros() {
+        assert_eq!(natural_cmp("p007", "p7"), Ordering::Less);
+        assert_eq!(natural_cmp("p007", "p08"), Ordering::Less);
+        assert_eq!(natural_cmp("p0", "p000"), Ordering::Less);
+        assert_eq!(sorted(&["10", "009", "1", "01"]), ["01", "1", "009", "10"]);
+    }
+
+    #[test]
+    fn natural_case() {
+        assert_eq!(
+            sorted(&["b.png", "A.png", "a.png", "B2.png", "b10.png"]),
+            ["A.png", "a.png", "B2.png", "b10.png", "b.png"]
+        );
+        assert_eq!(natural_cmp("Cover", "cover"), Ordering::Less);
+        assert_eq!(natural_cmp("cover", "COVERS"), Ordering::Less);
+    }
+
+    #[test]
+    fn natural_non_ascii() {
+        assert_eq!(
+            sorted(&["ページ10.png", "ページ2.png", "ページ1.png"]),
+            ["ページ1.png", "ページ2.png", "ページ10.png"]
+        );
+        assert_eq!(natural_cmp("Écran 2", "écran 10"), Ordering::Less);
+        assert_eq!(natural_cmp("é", "f"), Ordering::Greater);
+        // Only ASCII digits are compared by value
+        assert_eq!(natural_cmp("p２", "p10"), Ordering::Greater);
+        assert_eq!(natural_cmp("", "a"), Ordering::Less);
+    }
+
+    #[test]
+    fn sort_modes() {
+        let mut files: Vec<PathBuf> = ["b/p10.png", "a/p9.png", "c/x-1.png"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        sort_pages(&mut files, &SortMode::Natural).unwrap();
+        assert_eq!(
+            files,
+            [
+                Path::new("a/p9.png"),
+                Path::new("b/p10.png"),
+                Path::new("c/x-1.png")
+            ]
+        );
+        sort_pages(&mut files, &SortMode::Name).unwrap();
+        assert_eq!(files[0], Path::new("a/p9.png"));
+
+        // Ties on the page number fall back to natural order
+        let mut files = vec!["p3-b.png", "p2.png", "p3-a.png"];
+        let regex = SortMode::regex("p(\\d+)").unwrap();
+        sort_pages(&mut files, &regex).unwrap();
+        assert_eq!(files, ["p2.png", "p3-a.png", "p3-b.png"]);
+
+        let mut files = vec!["cover.png"];
+        let error = sort_pages(&mut files, &regex).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "No page number in 'cover.png' for --sort-regex"
+        );
+
+        assert_eq!(
+            SortMode::parse("size").err().unwrap().to_string(),
+            "Unknown sort mode 'size' (expected one of: natural, name, mtime, exif)"
+        );
+    }
+}
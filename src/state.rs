@@ -0,0 +1,191 @@
+//
+// Copyright 2024-2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+
+use crate::cache::Sha256;
+use crate::image::ImageFormat;
+
+// Pages encoded by an unfinished build, kept so that it can be resumed, with a
+// manifest of KEY SHA-256 FILE lines written as each page completes
+pub(crate) struct StateDir {
+    dir: PathBuf,
+    manifest: File,
+    pages: HashMap<String, PathBuf>,
+}
+
+impl StateDir {
+    const MANIFEST: &str = "manifest";
+
+    // Pages from an earlier run are kept only when resuming and still intact
+    pub(crate) fn open<P: AsRef<Path>>(dir: P, resume: bool) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        let manifest_path = dir.join(Self::MANIFEST);
+        if !resume && manifest_path.exists() {
+            fs::remove_dir_all(&dir)?;
+        } else if !manifest_path.exists()
+            && fs::read_dir(&dir).is_ok_and(|mut entries| entries.next().is_some())
+        {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("'{}' is not an mkcbt state directory", dir.display()),
+            ));
+        }
+        fs::create_dir_all(&dir)?;
+
+        let mut pages = HashMap::new();
+        let mut manifest = String::new();
+        if let Ok(text) = fs::read_to_string(&manifest_path) {
+            for line in text.lines() {
+                let mut fields = line.split(' ');
+                if let (Some(key), Some(hash), Some(file), None) =
+                    (fields.next(), fields.next(), fields.next(), fields.next())
+                    && !file.contains(['/', '\\'])
+                    && Sha256::file(&dir.join(file)).is_ok_and(|x| x == hash)
+                {
+                    pages.insert(key.to_string(), dir.join(file));
+                    manifest.push_str(&format!("{line}\n"));
+                }
+            }
+        }
+        fs::write(&manifest_path, manifest)?;
+
+        // Partial pages and pages that failed verification
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path != manifest_path && !pages.values().any(|x| *x == path) {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(Self {
+            manifest: File::options().append(true).open(&manifest_path)?,
+            dir,
+            pages,
+        })
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<PathBuf> {
+        self.pages.get(key).cloned()
+    }
+
+    // Where the encoder writes a page before it is added, apart for each page
+    // as identical pages share a key
+    pub(crate) fn partial_path(&self, key: &str, page: usize, format: ImageFormat) -> PathBuf {
+        self.dir
+            .join(format!("{key}.{page}.partial.{}", format.extension()))
+    }
+
+    pub(crate) fn add(&mut self, key: &str, partial: &Path) -> Result<PathBuf> {
+        let file = format!(
+            "{key}.{}",
+            partial.extension().unwrap_or_default().to_string_lossy()
+        );
+        let path = self.dir.join(&file);
+        File::open(partial)?.sync_all()?;
+        fs::rename(partial, &path)?;
+        writeln!(self.manifest, "{key} {} {file}", Sha256::file(&path)?)?;
+        self.manifest.sync_data()?;
+        self.pages.insert(key.to_string(), path.clone());
+        Ok(path)
+    }
+
+    // Directory left for a later run when the build is not completed
+    pub(crate) fn kept(&self) -> Option<&Path> {
+        (!self.pages.is_empty()).then_some(&self.dir)
+    }
+
+    // Only once the archive is complete
+    pub(crate) fn close(self) -> Result<()> {
+        fs::remove_dir_all(&self.dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::files::TempDir;
+
+    fn add_page(state: &mut StateDir, key: &str, data: &[u8]) -> PathBuf {
+        let partial = state.partial_path(key, 1, ImageFormat::Avif);
+        fs::write(&partial, data).unwrap();
+        state.add(key, &partial).unwrap()
+    }
+
+    fn files(dir: &Path) -> Vec<String> {
+        let mut names: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|x| x.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn resume_verifies_pages() {
+        let temp = TempDir::new("mkcbt-test").unwrap();
+        let dir = temp.path().join("state");
+        let mut state = StateDir::open(&dir, false).unwrap();
+        let kept = add_page(&mut state, "a", b"first");
+        let corrupted = add_page(&mut state, "b", b"second");
+        fs::write(state.partial_path("c", 3, ImageFormat::Avif), b"partial").unwrap();
+        drop(state);
+        fs::write(&corrupted, b"changed").unwrap();
+        fs::write(dir.join("stray"), b"").unwrap();
+
+        let state = StateDir::open(&dir, true).unwrap();
+        assert_eq!(state.get("a"), Some(kept));
+        assert_eq!(state.get("b"), None);
+        assert_eq!(files(&dir), ["a.avif", "manifest"]);
+        let manifest = fs::read_to_string(dir.join("manifest")).unwrap();
+        assert_eq!(manifest.lines().count(), 1);
+        assert!(manifest.starts_with("a "));
+    }
+
+    #[test]
+    fn fresh_start_clears_pages() {
+        let temp = TempDir::new("mkcbt-test").unwrap();
+        let dir = temp.path().join("state");
+        let mut state = StateDir::open(&dir, false).unwrap();
+        add_page(&mut state, "a", b"first");
+        assert_eq!(state.kept(), Some(dir.as_path()));
+        drop(state);
+
+        let state = StateDir::open(&dir, false).unwrap();
+        assert_eq!(state.get("a"), None);
+        assert_eq!(state.kept(), None);
+        assert_eq!(files(&dir), ["manifest"]);
+    }
+
+    #[test]
+    fn other_directory_refused() {
+        let temp = TempDir::new("mkcbt-test").unwrap();
+        fs::write(temp.path().join("notes.txt"), b"keep").unwrap();
+        for resume in [false, true] {
+            let error = StateDir::open(temp.path(), resume).err().unwrap();
+            assert_eq!(error.kind(), ErrorKind::AlreadyExists);
+        }
+        assert_eq!(files(temp.path()), ["notes.txt"]);
+    }
+}
@@ -0,0 +1,904 @@
+//
+// Copyright 2024-2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::files::{Spool, create_entry_file, safe_entry_path};
+
+/// Basic TAR files
+pub struct SimpleTarArchive {
+    writer: Box<dyn Write>,
+    offset: u64,
+    finished: bool,
+    appended: Option<Appended>,
+}
+
+// Existing archive, with the end of its last entry, where the new entries
+// start and its length before
+struct Appended {
+    file: File,
+    end: u64,
+    start: u64,
+    len: u64,
+}
+
+impl fmt::Debug for SimpleTarArchive {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SimpleTarArchive")
+            .field("offset", &self.offset)
+            .field("finished", &self.finished)
+            .field("appended", &self.appended.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl SimpleTarArchive {
+    const ZEROS: [u8; 1024] = [0; 1024];
+    const MAX_OCTAL_SIZE: u64 = 0o77777777777;
+
+    pub fn new(writer: impl Write + 'static) -> Self {
+        Self {
+            writer: Box::new(writer),
+            offset: 0,
+            finished: false,
+            appended: None,
+        }
+    }
+
+    /// Existing archive, continued after the given end of its last entry. New
+    /// entries are written past its end of archive blocks, so that it reads as
+    /// it was until they are linked in by finish, and are taken back out if the
+    /// archive is not finished.
+    pub fn append(mut file: File, end: u64) -> Result<Self> {
+        let len = file.metadata()?.len();
+        let start = len.max(end + 1024).next_multiple_of(512);
+        file.seek(SeekFrom::Start(start))?;
+        Ok(Self {
+            writer: Box::new(file.try_clone()?),
+            offset: start,
+            finished: false,
+            appended: Some(Appended {
+                file,
+                end,
+                start,
+                len,
+            }),
+        })
+    }
+
+    /// Write the end of the archive and hand back the writer
+    pub fn finish(mut self) -> Result<Box<dyn Write>> {
+        self.write_end()?;
+        if let Some(appended) = &mut self.appended {
+            Self::link(appended)?;
+        }
+        self.finished = true;
+        Ok(std::mem::replace(
+            &mut self.writer,
+            Box::new(std::io::sink()),
+        ))
+    }
+
+    // Blocks between the old entries and the new ones become a PAX global
+    // header holding a blank comment. Its header block replaces the old end
+    // of archive last, so the new entries appear all at once.
+    fn link(appended: &mut Appended) -> Result<()> {
+        let Appended {
+            file, end, start, ..
+        } = appended;
+        let size = *start - *end - 512;
+        let digits = size.to_string().len();
+        let padding = " ".repeat(size as usize - digits - 10);
+        file.sync_all()?;
+        file.seek(SeekFrom::Start(*end + 512))?;
+        file.write_all(format!("{size} comment={padding}\n").as_bytes())?;
+        file.sync_data()?;
+        file.seek(SeekFrom::Start(*end))?;
+        file.write_all(&Self::header(b"././@PaxHeader", b"", size, b'g'))?;
+        file.sync_data()?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+
+    fn write_end(&mut self) -> Result<()> {
+        // End of file padding
+        self.writer.write_all(&Self::ZEROS)?;
+        self.writer.flush()
+    }
+
+    /// Returns the offset of the file data
+    pub fn write_file<P: AsRef<Path>>(&mut self, path: P, file_name: &str) -> Result<u64> {
+        let mut file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        let offset = self.write_reader(&mut file, file_len, file_name)?;
+        if file.read(&mut [0])? != 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("'{file_name}' changed size while being archived"),
+            ));
+        }
+        Ok(offset)
+    }
+
+    pub fn write_bytes(&mut self, data: &[u8], file_name: &str) -> Result<u64> {
+        self.write_reader(data, data.len() as u64, file_name)
+    }
+
+    /// Reader must supply exactly the given length
+    pub fn write_reader(&mut self, reader: impl Read, len: u64, file_name: &str) -> Result<u64> {
+        // Write header
+        self.write_header(file_name, len, b'0')?;
+        let offset = self.offset;
+
+        // Copy data
+        let copied = std::io::copy(&mut reader.take(len), &mut self.writer)?;
+        self.offset += copied;
+        if copied != len {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("'{file_name}' ended after {copied} of {len} bytes"),
+            ));
+        }
+
+        // Add padding
+        self.write_padding(len)?;
+        Ok(offset)
+    }
+
+    /// Length is only known at the end, so the data is spooled first
+    pub fn write_stream(&mut self, reader: impl Read, file_name: &str) -> Result<u64> {
+        let mut spool = Spool::new(reader)?;
+        let len = spool.len()?;
+        let offset = self.write_reader(&mut spool, len, file_name)?;
+        spool.close()?;
+        Ok(offset)
+    }
+
+    pub fn write_dir(&mut self, dir_name: &str) -> Result<()> {
+        self.write_header(&format!("{}/", dir_name.trim_end_matches('/')), 0, b'5')
+    }
+
+    fn write_header(&mut self, file_name: &str, file_len: u64, type_flag: u8) -> Result<()> {
+        // Names and sizes that do not fit the ustar fields go in a PAX
+        // extended header, leaving a truncated name for old readers
+        let split = Self::split_name(file_name);
+        let mut records = String::new();
+        if split.is_none() {
+            records.push_str(&Self::pax_record("path", file_name));
+        }
+        if file_len > Self::MAX_OCTAL_SIZE {
+            records.push_str(&Self::pax_record("size", &file_len.to_string()));
+        }
+        if !records.is_empty() {
+            self.write_raw_header(b"././@PaxHeader", b"", records.len() as u64, b'x')?;
+            self.writer.write_all(records.as_bytes())?;
+            self.offset += records.len() as u64;
+            self.write_padding(records.len() as u64)?;
+        }
+
+        match split {
+            Some((prefix, name)) => self.write_raw_header(name, prefix, file_len, type_flag),
+            None => {
+                let fallback = Self::fallback_name(file_name);
+                self.write_raw_header(fallback.as_bytes(), b"", file_len, type_flag)
+            }
+        }
+    }
+
+    fn write_raw_header(
+        &mut self,
+        name: &[u8],
+        prefix: &[u8],
+        file_len: u64,
+        type_flag: u8,
+    ) -> Result<()> {
+        self.writer
+            .write_all(&Self::header(name, prefix, file_len, type_flag))?;
+        self.offset += 512;
+        Ok(())
+    }
+
+    fn header(name: &[u8], prefix: &[u8], file_len: u64, type_flag: u8) -> [u8; 512] {
+        let mut header = [0; 512];
+        header[..name.len()].copy_from_slice(name); // Filename
+        header[100..107].copy_from_slice(if type_flag == b'5' {
+            b"0000555"
+        } else {
+            b"0000444"
+        }); // Permissions
+        header[108..115].copy_from_slice(b"0000000"); // Owner ID
+        header[116..123].copy_from_slice(b"0000000"); // Group ID
+        if file_len > Self::MAX_OCTAL_SIZE {
+            // Base-256 for readers without PAX support (GNU extension)
+            header[124] = 0x80;
+            header[128..136].copy_from_slice(&file_len.to_be_bytes()); // File size
+        } else {
+            header[124..135].copy_from_slice(format!("{:011o}", file_len).as_bytes()); // File size
+        }
+        header[136..147].copy_from_slice(b"00000000000"); // Modification time
+        header[148..156].copy_from_slice(b"        "); // Checksum (for now)
+        header[156] = type_flag; // Link indicator
+        header[257..262].copy_from_slice(b"ustar"); // UStar indicator
+        header[263..265].copy_from_slice(b"00"); // UStar version
+        header[345..345 + prefix.len()].copy_from_slice(prefix); // Filename prefix
+
+        // Calculate checksum
+        let checksum: u32 = header.iter().map(|&x| x as u32).sum();
+        header[148..155].copy_from_slice(format!("{:06o}\0", checksum).as_bytes());
+        header
+    }
+
+    fn write_padding(&mut self, file_len: u64) -> Result<()> {
+        if !file_len.is_multiple_of(512) {
+            let padding = &Self::ZEROS[..(512 - file_len % 512) as usize];
+            self.writer.write_all(padding)?;
+            self.offset += padding.len() as u64;
+        }
+        Ok(())
+    }
+
+    // Split a name into ustar prefix and name fields, if possible
+    fn split_name(file_name: &str) -> Option<(&[u8], &[u8])> {
+        let bytes = file_name.as_bytes();
+        if !file_name.is_ascii() {
+            return None;
+        }
+        if bytes.len() <= 100 {
+            return Some((b"", bytes));
+        }
+        bytes
+            .iter()
+            .enumerate()
+            .filter(|&(i, &c)| c == b'/' && i <= 155 && bytes.len() - i - 1 <= 100)
+            .map(|(i, _)| (&bytes[..i], &bytes[i + 1..]))
+            .find(|(_, name)| !name.is_empty())
+    }
+
+    // PAX records are prefixed by their own length in decimal
+    fn pax_record(key: &str, value: &str) -> String {
+        let base_len = key.len() + value.len() + 3;
+        let mut len = base_len;
+        while len != base_len + len.to_string().len() {
+            len = base_len + len.to_string().len();
+        }
+        format!("{len} {key}={value}\n")
+    }
+
+    // ASCII name for readers without PAX support, keeping the extension
+    fn fallback_name(file_name: &str) -> String {
+        let ascii: String = file_name
+            .chars()
+            .map(|c| if c.is_ascii() && c != '\0' { c } else { '_' })
+            .collect();
+        ascii[ascii.len().saturating_sub(100)..].to_string()
+    }
+}
+
+// Best effort when not finished
+impl Drop for SimpleTarArchive {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        match self.appended.take() {
+            // Back to the old end of archive, in case linking got that far
+            Some(Appended {
+                mut file, end, len, ..
+            }) => {
+                let _ = self.writer.flush();
+                let _ = file
+                    .seek(SeekFrom::Start(end))
+                    .and_then(|_| file.write_all(&Self::ZEROS[..512]));
+                let _ = file.set_len(len);
+            }
+            None => {
+                let _ = self.write_end();
+            }
+        }
+    }
+}
+
+/// Reading TAR files
+#[derive(Debug)]
+pub struct TarEntry {
+    pub name: String,
+    pub size: u64,
+    pub offset: u64,
+    type_flag: u8,
+}
+
+impl TarEntry {
+    pub fn is_file(&self) -> bool {
+        self.type_flag == b'0' || self.type_flag == 0
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.type_flag == b'5'
+    }
+
+    /// Offset past the data and its padding
+    pub fn end(&self) -> u64 {
+        self.offset + self.size.div_ceil(512) * 512
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct SimpleTarReader<R: Read> {
+    reader: R,
+    position: u64,
+    remaining: u64,
+}
+
+impl<R: Read> SimpleTarReader<R> {
+    pub(crate) fn new(reader: R) -> Self {
+        Self {
+            reader,
+            position: 0,
+            remaining: 0,
+        }
+    }
+
+    pub(crate) fn next_entry(&mut self) -> Result<Option<TarEntry>> {
+        self.skip(self.remaining)?;
+        let mut long_name = None;
+        let mut pax_size = None;
+        loop {
+            let header_offset = self.position;
+            let mut header = [0; 512];
+            if !self.read_block(&mut header)? || header.iter().all(|&x| x == 0) {
+                return Ok(None);
+            }
+            Self::verify_checksum(&header, header_offset)?;
+
+            let size = Self::parse_number(&header[124..136], header_offset)?;
+            let type_flag = header[156];
+            match type_flag {
+                // PAX extended header
+                b'x' => {
+                    let data = self.read_data(size)?;
+                    let (path, size) = Self::parse_pax(&data, header_offset)?;
+                    long_name = path.or(long_name);
+                    pax_size = size.or(pax_size);
+                }
+                // GNU long name
+                b'L' => {
+                    let data = self.read_data(size)?;
+                    let end = data.iter().position(|&x| x == 0).unwrap_or(data.len());
+                    long_name = Some(String::from_utf8_lossy(&data[..end]).into_owned());
+                }
+                // PAX global header
+                b'g' => self.skip(size.div_ceil(512) * 512)?,
+                _ => {
+                    let name = long_name.unwrap_or_else(|| Self::parse_name(&header));
+                    let size = pax_size.unwrap_or(size);
+                    self.remaining = size.div_ceil(512) * 512;
+                    return Ok(Some(TarEntry {
+                        name,
+                        size,
+                        offset: self.position,
+                        type_flag,
+                    }));
+                }
+            }
+        }
+    }
+
+    pub(crate) fn copy_data(&mut self, entry: &TarEntry, writer: &mut impl Write) -> Result<()> {
+        let copied = std::io::copy(&mut (&mut self.reader).take(entry.size), writer)?;
+        self.position += copied;
+        self.remaining -= copied;
+        if copied != entry.size {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("'{}' is truncated", entry.name),
+            ));
+        }
+        Ok(())
+    }
+
+    // Unpack the remaining entries under the given directory
+    pub(crate) fn extract<P: AsRef<Path>>(&mut self, directory: P) -> Result<()> {
+        let directory = directory.as_ref();
+        while let Some(entry) = self.next_entry()? {
+            if entry.is_dir() {
+                fs::create_dir_all(directory.join(safe_entry_path(&entry.name)?))?;
+            } else if entry.is_file() {
+                let mut file = create_entry_file(directory, &entry.name)?;
+                self.copy_data(&entry, &mut file)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_block(&mut self, block: &mut [u8; 512]) -> Result<bool> {
+        let mut filled = 0;
+        while filled < block.len() {
+            match self.reader.read(&mut block[filled..])? {
+                0 if filled == 0 => return Ok(false),
+                0 => {
+                    return Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        format!("Truncated TAR header at offset {}", self.position),
+                    ));
+                }
+                n => filled += n,
+            }
+        }
+        self.position += 512;
+        Ok(true)
+    }
+
+    fn read_data(&mut self, size: u64) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        (&mut self.reader).take(size).read_to_end(&mut data)?;
+        self.position += data.len() as u64;
+        if (data.len() as u64) < size {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "Truncated TAR extended header",
+            ));
+        }
+        self.skip(size.div_ceil(512) * 512 - size)?;
+        Ok(data)
+    }
+
+    fn skip(&mut self, len: u64) -> Result<()> {
+        let skipped = std::io::copy(&mut (&mut self.reader).take(len), &mut std::io::sink())?;
+        self.position += skipped;
+        self.remaining = 0;
+        if skipped != len {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Truncated TAR file"));
+        }
+        Ok(())
+    }
+
+    // Checksum is the sum of the header bytes with the checksum field as spaces
+    fn verify_checksum(header: &[u8; 512], offset: u64) -> Result<()> {
+        let expected = Self::parse_number(&header[148..156], offset)?;
+        let unsigned: u64 = header[..148]
+            .iter()
+            .chain(header[156..].iter())
+            .map(|&x| x as u64)
+            .sum::<u64>()
+            + 8 * b' ' as u64;
+        let signed: i64 = header[..148]
+            .iter()
+            .chain(header[156..].iter())
+            .map(|&x| x as i8 as i64)
+            .sum::<i64>()
+            + 8 * b' ' as i64;
+        if expected != unsigned && expected as i64 != signed {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Bad TAR header checksum at offset {offset}"),
+            ));
+        }
+        Ok(())
+    }
+
+    // Octal, or base-256 if the high bit is set (GNU extension)
+    fn parse_number(field: &[u8], offset: u64) -> Result<u64> {
+        if field[0] & 0x80 != 0 {
+            return Ok(field[1..]
+                .iter()
+                .fold((field[0] & 0x7f) as u64, |acc, &x| (acc << 8) | x as u64));
+        }
+        let text: String = field
+            .iter()
+            .take_while(|&&x| x != 0)
+            .map(|&x| x as char)
+            .collect();
+        let text = text.trim();
+        if text.is_empty() {
+            return Ok(0);
+        }
+        u64::from_str_radix(text, 8).map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Bad numeric field in TAR header at offset {offset}"),
+            )
+        })
+    }
+
+    fn parse_name(header: &[u8; 512]) -> String {
+        let field = |bytes: &[u8]| {
+            let end = bytes.iter().position(|&x| x == 0).unwrap_or(bytes.len());
+            String::from_utf8_lossy(&bytes[..end]).into_owned()
+        };
+        let name = field(&header[..100]);
+        if &header[257..262] == b"ustar" && header[345] != 0 {
+            format!("{}/{}", field(&header[345..500]), name)
+        } else {
+            name
+        }
+    }
+
+    // Path and size records, the only ones that matter here
+    fn parse_pax(data: &[u8], offset: u64) -> Result<(Option<String>, Option<u64>)> {
+        let bad_record = || {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Bad PAX record at offset {offset}"),
+            )
+        };
+        let mut path = None;
+        let mut size = None;
+        let mut rest = data;
+        while !rest.is_empty() {
+            let space = rest
+                .iter()
+                .position(|&x| x == b' ')
+                .ok_or_else(bad_record)?;
+            let len: usize = std::str::from_utf8(&rest[..space])
+                .ok()
+                .and_then(|x| x.parse().ok())
+                .filter(|&x| x >= space + 2 && x <= rest.len() && rest[x - 1] == b'\n')
+                .ok_or_else(bad_record)?;
+            let record = &rest[space + 1..len - 1];
+            if let Some(value) = record.strip_prefix(b"path=") {
+                path = Some(String::from_utf8_lossy(value).into_owned());
+            } else if let Some(value) = record.strip_prefix(b"size=") {
+                let value = std::str::from_utf8(value).ok().and_then(|x| x.parse().ok());
+                size = Some(value.ok_or_else(bad_record)?);
+            }
+            rest = &rest[len..];
+        }
+        Ok((path, size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::files::{SharedBuffer, TempDir};
+
+    fn archive(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let buffer = SharedBuffer::default();
+        let mut tar = SimpleTarArchive::new(buffer.clone());
+        for (name, data) in entries {
+            tar.write_bytes(data, name).unwrap();
+        }
+        tar.finish().unwrap();
+        buffer.take()
+    }
+
+    fn read_all(data: &[u8]) -> Result<Vec<(String, Vec<u8>)>> {
+        let mut reader = SimpleTarReader::new(Cursor::new(data));
+        let mut entries = Vec::new();
+        while let Some(entry) = reader.next_entry()? {
+            let mut content = Vec::new();
+            reader.copy_data(&entry, &mut content)?;
+            entries.push((entry.name, content));
+        }
+        Ok(entries)
+    }
+
+    // Header block with a valid checksum
+    fn header(name: &str, size: u64, type_flag: u8) -> [u8; 512] {
+        let mut header = [0; 512];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[124..135].copy_from_slice(format!("{size:011o}").as_bytes());
+        header[148..156].copy_from_slice(b"        ");
+        header[156] = type_flag;
+        header[257..262].copy_from_slice(b"ustar");
+        let checksum: u32 = header.iter().map(|&x| x as u32).sum();
+        header[148..155].copy_from_slice(format!("{checksum:06o}\0").as_bytes());
+        header
+    }
+
+    // Extended header followed by a regular entry
+    fn extended(type_flag: u8, data: &[u8]) -> Vec<u8> {
+        let mut tar = header("ext", data.len() as u64, type_flag).to_vec();
+        tar.extend_from_slice(data);
+        tar.resize(tar.len().div_ceil(512) * 512, 0);
+        tar.extend_from_slice(&header("short", 0, b'0'));
+        tar.extend_from_slice(&[0; 1024]);
+        tar
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = archive(&[("a.png", b"hello"), ("dir/b.png", &[7; 600])]);
+        assert_eq!(data.len() % 512, 0);
+        assert_eq!(
+            read_all(&data).unwrap(),
+            [
+                ("a.png".to_string(), b"hello".to_vec()),
+                ("dir/b.png".to_string(), vec![7; 600]),
+            ]
+        );
+    }
+
+    #[test]
+    fn long_names_round_trip() {
+        let prefixed = format!("{}/{}.png", "d".repeat(120), "n".repeat(90));
+        let long = format!("{}.png", "x".repeat(300));
+        let unicode = "chapter/ページ 1.png";
+        let data = archive(&[(&prefixed, b"1"), (&long, b"2"), (unicode, b"3")]);
+        let names: Vec<_> = read_all(&data).unwrap().into_iter().map(|x| x.0).collect();
+        assert_eq!(names, [prefixed.as_str(), long.as_str(), unicode]);
+    }
+
+    #[test]
+    fn split_name() {
+        assert_eq!(
+            SimpleTarArchive::split_name("a.png"),
+            Some((&b""[..], &b"a.png"[..]))
+        );
+        let name = format!("{}/{}", "d".repeat(150), "n".repeat(100));
+        let (prefix, rest) = SimpleTarArchive::split_name(&name).unwrap();
+        assert_eq!((prefix.len(), rest.len()), (150, 100));
+        assert_eq!(SimpleTarArchive::split_name(&"x".repeat(101)), None);
+        assert_eq!(SimpleTarArchive::split_name("ü.png"), None);
+    }
+
+    #[test]
+    fn pax_record_length() {
+        assert_eq!(SimpleTarArchive::pax_record("path", "a"), "9 path=a\n");
+        // Length crossing into another digit counts itself
+        let record = SimpleTarArchive::pax_record("path", &"x".repeat(91));
+        assert_eq!(record.len(), 101);
+        assert!(record.starts_with("101 "));
+    }
+
+    #[test]
+    fn fallback_name() {
+        assert_eq!(SimpleTarArchive::fallback_name("ü.png"), "_.png");
+        let name = SimpleTarArchive::fallback_name(&format!("{}.png", "x".repeat(200)));
+        assert_eq!(name.len(), 100);
+        assert!(name.ends_with(".png"));
+    }
+
+    #[test]
+    fn entry_end() {
+        let data = archive(&[("a", &[1; 513]), ("b", b"")]);
+        let mut reader = SimpleTarReader::new(Cursor::new(&data));
+        let entry = reader.next_entry().unwrap().unwrap();
+        assert_eq!((entry.offset, entry.end()), (512, 1536));
+        let entry = reader.next_entry().unwrap().unwrap();
+        assert_eq!((entry.offset, entry.end()), (2048, 2048));
+        assert!(reader.next_entry().unwrap().is_none());
+    }
+
+    #[test]
+    fn checksum_rejected() {
+        let mut data = archive(&[("a.png", b"hello")]);
+        data[0] = b'b';
+        let error = read_all(&data).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "Bad TAR header checksum at offset 0");
+    }
+
+    #[test]
+    fn signed_checksum_accepted() {
+        // Some old writers summed the header as signed bytes
+        let mut block = header("\u{e9}.png", 0, b'0');
+        let signed: i64 = block[..148]
+            .iter()
+            .chain(&block[156..])
+            .map(|&x| x as i8 as i64)
+            .sum::<i64>()
+            + 8 * b' ' as i64;
+        block[148..155].copy_from_slice(format!("{signed:06o}\0").as_bytes());
+        assert!(SimpleTarReader::<&[u8]>::verify_checksum(&block, 0).is_ok());
+    }
+
+    #[test]
+    fn parse_number() {
+        let parse = |field: &[u8]| SimpleTarReader::<&[u8]>::parse_number(field, 0);
+        assert_eq!(parse(b"00000000017\0").unwrap(), 15);
+        assert_eq!(parse(b"  17 \0").unwrap(), 15);
+        assert_eq!(parse(b"\0\0\0").unwrap(), 0);
+        assert_eq!(parse(&[0x80, 0, 0, 2, 0]).unwrap(), 512);
+        assert!(parse(b"0009\0").is_err());
+    }
+
+    #[test]
+    fn pax_path() {
+        let data = extended(b'x', b"9 path=a\n13 mtime=1.5\n");
+        assert_eq!(read_all(&data).unwrap()[0].0, "a");
+        // Records without a path keep the header name
+        let data = extended(b'x', b"13 mtime=1.5\n");
+        assert_eq!(read_all(&data).unwrap()[0].0, "short");
+    }
+
+    #[test]
+    fn malformed_pax_rejected() {
+        for record in [
+            &b"2 "[..],
+            b"11 path=a",
+            b"8 path=a\n",
+            b"99 path=a\n",
+            b"x path=a\n",
+            b"path=a\n",
+        ] {
+            let error = read_all(&extended(b'x', record)).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidData, "{record:?}");
+            assert_eq!(error.to_string(), "Bad PAX record at offset 0");
+        }
+    }
+
+    #[test]
+    fn gnu_long_name() {
+        let name = "y".repeat(150);
+        let data = extended(b'L', format!("{name}\0").as_bytes());
+        assert_eq!(read_all(&data).unwrap()[0].0, name);
+    }
+
+    #[test]
+    fn malformed_gnu_rejected() {
+        // Long name claims more data than the archive holds
+        let mut data = header("././@LongLink", 4096, b'L').to_vec();
+        data.extend_from_slice(b"name\0");
+        let error = read_all(&data).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+
+        let mut data = header("././@LongLink", 0, b'L').to_vec();
+        data[124..135].copy_from_slice(b"0000000008x");
+        let checksum: u32 = data[..148]
+            .iter()
+            .chain(&data[156..])
+            .map(|&x| x as u32)
+            .sum::<u32>()
+            + 8 * b' ' as u32;
+        data[148..155].copy_from_slice(format!("{checksum:06o}\0").as_bytes());
+        let error = read_all(&data).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Bad numeric field in TAR header at offset 0"
+        );
+    }
+
+    #[test]
+    fn truncated_header() {
+        let data = archive(&[("a.png", b"hello")]);
+        let error = read_all(&data[..100]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+        let error = read_all(&data[..515]).unwrap_err();
+        assert_eq!(error.to_string(), "'a.png' is truncated");
+        let error = read_all(&data[..600]).unwrap_err();
+        assert_eq!(error.to_string(), "Truncated TAR file");
+    }
+
+    #[test]
+    fn large_sizes() {
+        // Headers only, as the data would not fit in memory
+        let size = 9 << 30;
+        let buffer = SharedBuffer::default();
+        let mut tar = SimpleTarArchive::new(buffer.clone());
+        tar.write_header("big.png", size, b'0').unwrap();
+        tar.write_header(&"x".repeat(120), size, b'0').unwrap();
+        tar.write_header("small.png", SimpleTarArchive::MAX_OCTAL_SIZE, b'0')
+            .unwrap();
+        drop(tar);
+        let data = buffer.take();
+        assert_eq!(data.len(), 7 * 512 + 1024);
+        assert_eq!(&data[512..531], b"19 size=9663676416\n");
+        assert_eq!(data[1024 + 124], 0x80);
+
+        // Each header read on its own, as the data is missing
+        let read = |offset: usize| {
+            let mut reader = SimpleTarReader::new(&data[offset..]);
+            reader.next_entry().unwrap().unwrap()
+        };
+        let entry = read(0);
+        assert_eq!((entry.name.as_str(), entry.size), ("big.png", size));
+        let entry = read(1536);
+        assert_eq!((entry.name, entry.size), ("x".repeat(120), size));
+        assert_eq!(read(3072).size, SimpleTarArchive::MAX_OCTAL_SIZE);
+        assert_eq!(&data[3072 + 124..3072 + 136], b"77777777777\0");
+
+        // PAX size wins over the header field
+        let data = extended(b'x', b"12 size=100\n");
+        let mut reader = SimpleTarReader::new(Cursor::new(&data));
+        assert_eq!(reader.next_entry().unwrap().unwrap().size, 100);
+        let error = read_all(&extended(b'x', b"11 size=1x\n")).unwrap_err();
+        assert_eq!(error.to_string(), "Bad PAX record at offset 0");
+    }
+
+    // Archive file in a temporary directory with the given entries
+    fn archive_file(dir: &TempDir, entries: &[(&str, &[u8])]) -> (PathBuf, u64) {
+        let path = dir.path().join("a.cbt");
+        fs::write(&path, archive(entries)).unwrap();
+        let end = last_end(&path);
+        (path, end)
+    }
+
+    fn last_end(path: &Path) -> u64 {
+        let mut reader = SimpleTarReader::new(File::open(path).unwrap());
+        let mut end = 0;
+        while let Some(entry) = reader.next_entry().unwrap() {
+            end = entry.end();
+        }
+        end
+    }
+
+    fn append(path: &Path, end: u64) -> SimpleTarArchive {
+        let file = File::options().read(true).write(true).open(path).unwrap();
+        SimpleTarArchive::append(file, end).unwrap()
+    }
+
+    #[test]
+    fn append_in_place() {
+        let dir = TempDir::new("mkcbt-test").unwrap();
+        let (path, end) = archive_file(&dir, &[("1.png", b"one")]);
+        let mut tar = append(&path, end);
+        assert_eq!(tar.write_bytes(b"two", "2.png").unwrap(), 2560);
+        tar.finish().unwrap();
+        let data = fs::read(&path).unwrap();
+        assert_eq!(
+            read_all(&data).unwrap(),
+            [
+                ("1.png".to_string(), b"one".to_vec()),
+                ("2.png".to_string(), b"two".to_vec()),
+            ]
+        );
+        // Old end of archive became a global header over the gap
+        assert_eq!(data[end as usize + 156], b'g');
+        assert_eq!(data.len(), 2560 + 512 + 1024);
+
+        // And once more after that
+        let mut tar = append(&path, last_end(&path));
+        tar.write_bytes(b"three", "3.png").unwrap();
+        tar.finish().unwrap();
+        let names: Vec<_> = read_all(&fs::read(&path).unwrap())
+            .unwrap()
+            .into_iter()
+            .map(|x| x.0)
+            .collect();
+        assert_eq!(names, ["1.png", "2.png", "3.png"]);
+    }
+
+    #[test]
+    fn append_rolled_back() {
+        let dir = TempDir::new("mkcbt-test").unwrap();
+        let (path, end) = archive_file(&dir, &[("1.png", b"one")]);
+        let original = fs::read(&path).unwrap();
+        let mut tar = append(&path, end);
+        tar.write_bytes(&[7; 5000], "2.png").unwrap();
+        drop(tar);
+        assert_eq!(fs::read(&path).unwrap(), original);
+    }
+
+    #[test]
+    fn append_interrupted() {
+        // Killed before finishing, so nothing is rolled back
+        let dir = TempDir::new("mkcbt-test").unwrap();
+        let (path, end) = archive_file(&dir, &[("1.png", b"one")]);
+        let mut tar = append(&path, end);
+        tar.write_bytes(&[7; 5000], "2.png").unwrap();
+        tar.writer.flush().unwrap();
+        std::mem::forget(tar);
+        let names: Vec<_> = read_all(&fs::read(&path).unwrap())
+            .unwrap()
+            .into_iter()
+            .map(|x| x.0)
+            .collect();
+        assert_eq!(names, ["1.png"]);
+    }
+}
@@ -0,0 +1,1266 @@
+//
+// Copyright 2024-2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+use std::{fmt, fs};
+
+use crate::archive::{ArchiveFormat, ArchiveWriter};
+use crate::cache::{EncodeCache, Sha256};
+use crate::comicinfo::{ComicInfo, PageInfo};
+use crate::encoder::{Encoder, EncoderSettings};
+use crate::error::{CbtError, CbtResult};
+use crate::files::{AtomicFile, TempDir};
+use crate::image::{ImageFormat, image_dimensions};
+use crate::progress::Progress;
+use crate::signal::check_signal;
+use crate::state::StateDir;
+use crate::tar::{SimpleTarArchive, SimpleTarReader, TarEntry};
+
+/// Handling of pages the encoder fails on
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FailurePolicy {
+    #[default]
+    Abort,
+    Store,
+    Skip,
+}
+
+impl FailurePolicy {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "abort" => Ok(Self::Abort),
+            "store" => Ok(Self::Store),
+            "skip" => Ok(Self::Skip),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unknown failure policy '{name}' (expected one of: abort, store, skip)"),
+            )),
+        }
+    }
+}
+
+/// Page left unconverted under the store or skip policy
+#[derive(Debug)]
+pub struct PageFailure {
+    pub page: usize,
+    pub input: PathBuf,
+    pub status: ExitStatus,
+    pub stored: bool,
+}
+
+impl fmt::Display for PageFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "page {} ('{}'): {}, {}",
+            self.page,
+            self.input.display(),
+            self.status,
+            if self.stored {
+                "stored original"
+            } else {
+                "skipped"
+            }
+        )
+    }
+}
+
+/// Pages written to the archive, and those that could not be converted
+#[derive(Debug)]
+pub struct CbtSummary {
+    pub pages: usize,
+    pub failures: Vec<PageFailure>,
+}
+
+// Running encoder, with its error output going to a log file
+struct Conversion {
+    process: Child,
+    page: usize,
+    input: PathBuf,
+    format: ImageFormat,
+    output: PathBuf,
+    log: PathBuf,
+    key: Option<String>,
+    started: Instant,
+}
+
+impl Conversion {
+    // Lines of encoder output kept in errors
+    const LOG_LINES: usize = 20;
+
+    fn stderr(&self) -> String {
+        let log = fs::read(&self.log).unwrap_or_default();
+        let log = String::from_utf8_lossy(&log);
+        let lines: Vec<_> = log.trim_end().lines().collect();
+        lines[lines.len().saturating_sub(Self::LOG_LINES)..].join("\n")
+    }
+
+    // Polled so that interrupts are noticed
+    fn wait(&mut self) -> CbtResult<ExitStatus> {
+        loop {
+            check_signal()?;
+            if let Some(status) = self.process.try_wait()? {
+                // Encoder may have been stopped by the same interrupt
+                check_signal()?;
+                return Ok(status);
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+// Encoders still running when abandoned are stopped
+impl Drop for Conversion {
+    fn drop(&mut self) {
+        if let Ok(None) = self.process.try_wait() {
+            let _ = self.process.kill();
+            let _ = self.process.wait();
+        }
+    }
+}
+
+/// Steps of building an archive, reported to frontends
+#[derive(Debug)]
+pub enum CbtEvent<'a> {
+    Submitted {
+        page: usize,
+        input: &'a Path,
+        name: &'a str,
+    },
+    EncoderStarted {
+        page: usize,
+        program: &'static str,
+        pid: u32,
+    },
+    EncoderFinished {
+        page: usize,
+        status: ExitStatus,
+        elapsed: Duration,
+    },
+    PageWritten {
+        page: usize,
+        name: &'a str,
+        offset: u64,
+        size: u64,
+    },
+    Warning(&'a str),
+}
+
+pub(crate) type EventHandler = Box<dyn FnMut(CbtEvent)>;
+
+// Directory, number and digits of a page name such as "Chapter 1/007.avif"
+fn page_number(name: &str) -> Option<(&str, usize, usize)> {
+    let (chapter, file) = name.rsplit_once('/').unwrap_or(("", name));
+    let (stem, _) = file.rsplit_once('.')?;
+    if stem.is_empty() || !stem.bytes().all(|x| x.is_ascii_digit()) {
+        return None;
+    }
+    Some((chapter, stem.parse().ok()?, stem.len()))
+}
+
+// Same page number, another extension
+fn page_name_with_format(name: &str, format: ImageFormat) -> String {
+    let stem = name.rsplit_once('.').map_or(name, |(stem, _)| stem);
+    format!("{stem}.{}", format.extension())
+}
+
+// Pages to copy carry the size of their source, for progress
+enum CbtWriterJob {
+    Copy(PathBuf, String, u64),
+    Convert(Conversion, String),
+    // Same source and settings as a page still being converted, so its result
+    // is reused once that one is done
+    Duplicate {
+        page: usize,
+        input: PathBuf,
+        format: ImageFormat,
+        key: String,
+        name: String,
+    },
+    Directory(String),
+}
+
+pub struct CbtWriter {
+    archive: ArchiveWriter,
+    output: Option<AtomicFile>,
+    jobs: VecDeque<CbtWriterJob>,
+    index: usize,
+    padding: usize,
+    processes: usize,
+    threads_per_job: usize,
+    max_ahead: Option<usize>,
+    work_dir: TempDir,
+    submitted: usize,
+    chapter: String,
+    chapter_indices: HashMap<String, usize>,
+    directories: HashSet<String>,
+    bookmarks: Vec<(usize, String)>,
+    skipped: Vec<usize>,
+    unreadable: Vec<ImageFormat>,
+    pages: Vec<PageInfo>,
+    comic_info: Option<ComicInfo>,
+    comic_info_file: Option<PathBuf>,
+    existing_comic_info: bool,
+    encoder: Encoder,
+    settings: EncoderSettings,
+    on_error: FailurePolicy,
+    failures: Vec<PageFailure>,
+    min_savings: Option<u8>,
+    cache: Option<EncodeCache>,
+    state: Option<StateDir>,
+    versions: HashMap<&'static str, Option<String>>,
+    progress: Option<Progress>,
+    events: Option<EventHandler>,
+    completed: usize,
+}
+
+// Running encoders and the archive stream are left out
+impl fmt::Debug for CbtWriter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CbtWriter")
+            .field("submitted", &self.submitted)
+            .field("completed", &self.completed)
+            .field("chapter", &self.chapter)
+            .field("encoder", &self.encoder)
+            .field("settings", &self.settings)
+            .field("on_error", &self.on_error)
+            .field("failures", &self.failures)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CbtWriter {
+    // Default bound on pages queued per encoder
+    const AHEAD_PER_PROCESS: usize = 4;
+
+    pub fn new(
+        writer: impl Write + 'static,
+        format: ArchiveFormat,
+        padding: usize,
+    ) -> Result<Self> {
+        Self::with_archive(ArchiveWriter::new(writer, format), None, padding)
+    }
+
+    /// Target is only replaced once finish succeeds
+    pub fn create<P: AsRef<Path>>(path: P, format: ArchiveFormat, padding: usize) -> Result<Self> {
+        let output = AtomicFile::create(path)?;
+        let archive = ArchiveWriter::new(output.writer()?, format);
+        Self::with_archive(archive, Some(output), padding)
+    }
+
+    /// Existing CBT archive, with page numbers continued in each directory for
+    /// new pages in the given chapters (one per page). Pages are added in place,
+    /// unless their numbers need more digits and existing pages are renamed in
+    /// a rebuilt archive
+    pub fn append<'a, P: AsRef<Path>>(
+        path: P,
+        chapters: impl IntoIterator<Item = &'a str>,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.is_file() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("'{}' does not exist", path.display()),
+            ));
+        }
+        if ArchiveFormat::detect(path)? != Some(ArchiveFormat::Cbt) {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("'{}' is not a CBT archive", path.display()),
+            ));
+        }
+        let mut reader = SimpleTarReader::new(BufReader::new(File::open(path)?));
+        let mut entries = Vec::new();
+        while let Some(entry) = reader.next_entry()? {
+            entries.push(entry);
+        }
+
+        // Last page number in each directory, and the digits they are written with
+        let mut indices: HashMap<String, usize> = HashMap::new();
+        let mut directories = HashSet::new();
+        let mut padding = 1;
+        for entry in &entries {
+            if entry.is_dir() {
+                directories.insert(entry.name.trim_end_matches('/').to_string());
+            } else if let Some((chapter, index, digits)) = page_number(&entry.name) {
+                let last = indices.entry(chapter.to_string()).or_default();
+                *last = index.max(*last);
+                padding = padding.max(digits);
+            }
+        }
+        let mut added: HashMap<&str, usize> = HashMap::new();
+        for chapter in chapters {
+            *added.entry(chapter).or_default() += 1;
+        }
+        let highest = added
+            .iter()
+            .map(|(chapter, pages)| indices.get(*chapter).copied().unwrap_or(0) + pages)
+            .max()
+            .unwrap_or(1);
+        let digits = highest.ilog10() as usize + 1;
+
+        let mut cbt = if digits <= padding {
+            let end = entries.last().map_or(0, TarEntry::end);
+            let file = File::options().read(true).write(true).open(path)?;
+            let archive = ArchiveWriter::Tar(SimpleTarArchive::append(file, end)?);
+            Self::with_archive(archive, None, padding)?
+        } else {
+            let output = AtomicFile::create(path)?;
+            let archive = ArchiveWriter::new(output.writer()?, ArchiveFormat::Cbt);
+            let mut cbt = Self::with_archive(archive, Some(output), digits)?;
+            cbt.copy_renamed(path)?;
+            cbt
+        };
+        cbt.existing_comic_info = entries.iter().any(|x| x.name == "ComicInfo.xml");
+        cbt.directories = directories;
+        cbt.index = indices.remove("").map_or(1, |index| index + 1);
+        cbt.chapter_indices = indices
+            .into_iter()
+            .map(|(chapter, index)| (chapter, index + 1))
+            .collect();
+        Ok(cbt)
+    }
+
+    // Entries of the archive at the given path, with page numbers in the
+    // current padding
+    fn copy_renamed(&mut self, path: &Path) -> Result<()> {
+        let mut reader = SimpleTarReader::new(BufReader::new(File::open(path)?));
+        while let Some(entry) = reader.next_entry()? {
+            if entry.is_dir() {
+                self.archive.write_dir(&entry.name)?;
+            } else if entry.is_file() {
+                let name = match page_number(&entry.name) {
+                    Some((chapter, index, _)) => {
+                        let (_, ext) = entry.name.rsplit_once('.').unwrap();
+                        let name = format!("{index:0fill$}.{ext}", fill = self.padding);
+                        match chapter {
+                            "" => name,
+                            _ => format!("{chapter}/{name}"),
+                        }
+                    }
+                    None => entry.name.clone(),
+                };
+                let mut data = Vec::with_capacity(entry.size as usize);
+                reader.copy_data(&entry, &mut data)?;
+                self.archive.write_bytes(&data, &name)?;
+            } else {
+                self.warn(&format!(
+                    "Leaving out '{}', which is not a file",
+                    entry.name
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn with_archive(
+        archive: ArchiveWriter,
+        output: Option<AtomicFile>,
+        padding: usize,
+    ) -> Result<Self> {
+        let processes = std::thread::available_parallelism()?.get();
+        Ok(Self {
+            archive,
+            output,
+            jobs: VecDeque::with_capacity(processes),
+            index: 1,
+            padding,
+            processes,
+            threads_per_job: 1,
+            max_ahead: None,
+            work_dir: TempDir::new("mkcbt")?,
+            submitted: 0,
+            chapter: String::new(),
+            chapter_indices: HashMap::new(),
+            directories: HashSet::new(),
+            bookmarks: Vec::new(),
+            skipped: Vec::new(),
+            unreadable: Vec::new(),
+            pages: Vec::new(),
+            comic_info: None,
+            comic_info_file: None,
+            existing_comic_info: false,
+            encoder: Encoder::Avif,
+            settings: EncoderSettings::preset("archive")?,
+            on_error: FailurePolicy::Abort,
+            failures: Vec::new(),
+            min_savings: None,
+            cache: None,
+            state: None,
+            versions: HashMap::new(),
+            progress: None,
+            events: None,
+            completed: 0,
+        })
+    }
+
+    /// Following pages go into the given directory, numbered from where it left off
+    pub fn set_chapter(&mut self, chapter: &str) {
+        if chapter == self.chapter {
+            return;
+        }
+        let previous = std::mem::replace(&mut self.chapter, chapter.to_string());
+        self.chapter_indices.insert(previous, self.index);
+        if let Some(&index) = self.chapter_indices.get(chapter) {
+            self.index = index;
+            return;
+        }
+
+        // New chapter, so add its directory (and any parents) to the archive
+        self.index = 1;
+        let ends = chapter
+            .match_indices('/')
+            .map(|(i, _)| i)
+            .chain([chapter.len()]);
+        for end in ends {
+            if self.directories.insert(chapter[..end].to_string()) {
+                self.jobs
+                    .push_back(CbtWriterJob::Directory(chapter[..end].to_string()));
+            }
+        }
+        let title = chapter.rsplit('/').next().unwrap_or(chapter);
+        self.bookmarks.push((self.submitted, title.to_string()));
+    }
+
+    pub fn submit(&mut self, path: &Path, format: ImageFormat) -> CbtResult<()> {
+        self.make_room()?;
+        // Pages already in the target format are stored as they are, and so
+        // are pages the encoder can not read
+        let target = match self.encoder.target() {
+            Some(target) if self.encoder.reads(format) => target,
+            Some(target) if target != format && !self.unreadable.contains(&format) => {
+                self.unreadable.push(format);
+                self.warn(&format!(
+                    "{} can not read {} pages, so they are stored as they are",
+                    self.encoder.program(),
+                    format.extension().to_ascii_uppercase()
+                ));
+                format
+            }
+            _ => format,
+        };
+        let mut name = format!(
+            "{:0fill$}.{}",
+            self.index,
+            target.extension(),
+            fill = self.padding
+        );
+        if !self.chapter.is_empty() {
+            name = format!("{}/{name}", self.chapter);
+        }
+        self.emit(CbtEvent::Submitted {
+            page: self.submitted + 1,
+            input: path,
+            name: &name,
+        });
+        let key = match target != format {
+            true => self.page_key(path, format)?,
+            false => None,
+        };
+        let done = key.as_ref().and_then(|key| self.lookup(key, target));
+        let in_flight = key.as_ref().is_some_and(|key| {
+            self.jobs.iter().any(|job| match job {
+                CbtWriterJob::Convert(conversion, _) => conversion.key.as_ref() == Some(key),
+                _ => false,
+            })
+        });
+        if let Some(cached) = done {
+            let source_len = path.metadata()?.len();
+            let job = if self.worth_keeping(path, &cached)? {
+                CbtWriterJob::Copy(cached, name, source_len)
+            } else {
+                let name = page_name_with_format(&name, format);
+                CbtWriterJob::Copy(path.to_path_buf(), name, source_len)
+            };
+            self.jobs.push_back(job);
+        } else if in_flight && let Some(key) = key {
+            self.jobs.push_back(CbtWriterJob::Duplicate {
+                page: self.submitted + 1,
+                input: path.to_path_buf(),
+                format,
+                key,
+                name,
+            });
+        } else if target != format {
+            let conversion = self.spawn(self.submitted + 1, path, format, target, key)?;
+            self.jobs.push_back(CbtWriterJob::Convert(conversion, name));
+        } else {
+            self.jobs.push_back(CbtWriterJob::Copy(
+                path.to_path_buf(),
+                name,
+                path.metadata()?.len(),
+            ));
+        }
+        self.index += 1;
+        self.submitted += 1;
+        Ok(())
+    }
+
+    // Start the encoder on the given page
+    fn spawn(
+        &mut self,
+        page: usize,
+        path: &Path,
+        format: ImageFormat,
+        target: ImageFormat,
+        key: Option<String>,
+    ) -> CbtResult<Conversion> {
+        let tmp_path = match (&self.state, &key) {
+            (Some(state), Some(key)) => state.partial_path(key, page, target),
+            _ => self
+                .work_dir
+                .path()
+                .join(format!("{page}.{}", target.extension())),
+        };
+        let log = self.work_dir.path().join(format!("{page}.log"));
+        let process = self
+            .encoder
+            .command(
+                &self.settings,
+                self.threads_per_job,
+                path,
+                format,
+                &tmp_path,
+            )
+            .stdout(Stdio::null())
+            .stderr(File::create(&log)?)
+            .spawn()
+            .map_err(|source| CbtError::Spawn {
+                program: self.encoder.program(),
+                page,
+                input: path.to_path_buf(),
+                source,
+            })?;
+        self.emit(CbtEvent::EncoderStarted {
+            page,
+            program: self.encoder.program(),
+            pid: process.id(),
+        });
+        Ok(Conversion {
+            process,
+            page,
+            input: path.to_path_buf(),
+            format,
+            output: tmp_path,
+            log,
+            key,
+            started: Instant::now(),
+        })
+    }
+
+    // Page encoded earlier, in this build or a previous one
+    fn lookup(&self, key: &str, target: ImageFormat) -> Option<PathBuf> {
+        let state = self.state.as_ref().and_then(|state| state.get(key));
+        state.or_else(|| self.cache.as_ref()?.lookup(key, target))
+    }
+
+    /// Complete the archive, returning the writer and what was written
+    pub fn finish(mut self) -> CbtResult<(Box<dyn Write>, CbtSummary)> {
+        while let Some(job) = self.jobs.pop_front() {
+            self.complete_job(job)?;
+        }
+        if let Some(progress) = &mut self.progress {
+            progress.finish();
+        }
+        // Bookmarks are by submission, so move them up past skipped pages
+        for (page, title) in self.bookmarks.drain(..) {
+            let page = page - self.skipped.iter().filter(|&&x| x < page).count();
+            if let Some(page) = self.pages.get_mut(page) {
+                page.bookmark = Some(title);
+            }
+        }
+        if let Some(comic_info) = &self.comic_info {
+            let xml = comic_info.to_xml(&self.pages);
+            self.archive.write_bytes(xml.as_bytes(), "ComicInfo.xml")?;
+        } else if let Some(path) = &self.comic_info_file {
+            self.archive.write_file(path, "ComicInfo.xml")?;
+        } else if self.existing_comic_info {
+            self.warn("Existing ComicInfo.xml does not list the added pages");
+        }
+
+        // Archive is swapped for one on a sink, as the writer is dropped later
+        let placeholder = ArchiveWriter::new(std::io::sink(), ArchiveFormat::Cbt);
+        let writer = std::mem::replace(&mut self.archive, placeholder).finish()?;
+        if let Some(output) = self.output.take() {
+            output.commit()?;
+        }
+        self.work_dir.close()?;
+        if let Some(state) = self.state.take() {
+            state.close()?;
+        }
+        let summary = CbtSummary {
+            pages: self.completed - self.skipped.len(),
+            failures: std::mem::take(&mut self.failures),
+        };
+        Ok((writer, summary))
+    }
+
+    pub fn set_comic_info(&mut self, comic_info: ComicInfo) {
+        self.comic_info = Some(comic_info);
+    }
+
+    /// Existing ComicInfo.xml to store as it is
+    pub fn set_comic_info_file(&mut self, path: PathBuf) {
+        self.comic_info_file = Some(path);
+    }
+
+    pub fn set_encoder(&mut self, encoder: Encoder, settings: EncoderSettings) {
+        self.encoder = encoder;
+        self.settings = settings;
+    }
+
+    /// Encoders run at once
+    pub fn set_jobs(&mut self, jobs: usize) {
+        self.processes = jobs.max(1);
+    }
+
+    pub fn set_threads_per_job(&mut self, threads: usize) {
+        self.threads_per_job = threads.max(1);
+    }
+
+    /// Pages that may be queued or converted before the oldest one is written
+    pub fn set_max_ahead(&mut self, pages: usize) {
+        self.max_ahead = Some(pages.max(1));
+    }
+
+    /// Store the original instead when conversion saves less than the given percentage
+    pub fn set_keep_smaller(&mut self, min_savings: u8) {
+        self.min_savings = Some(min_savings.min(99));
+    }
+
+    /// Reuse pages encoded by earlier runs, and keep new ones for later runs
+    pub fn set_cache(&mut self, cache: EncodeCache) {
+        self.cache = Some(cache);
+    }
+
+    // Keep encoded pages in a state directory until the archive is done
+    pub(crate) fn set_state_dir(&mut self, state: StateDir) {
+        self.state = Some(state);
+    }
+
+    /// Events replace warnings printed to standard error
+    pub fn set_event_handler(&mut self, handler: impl FnMut(CbtEvent) + 'static) {
+        self.events = Some(Box::new(handler));
+    }
+
+    fn emit(&mut self, event: CbtEvent) {
+        if let Some(handler) = &mut self.events {
+            handler(event);
+        }
+    }
+
+    /// Report each page written out of the given total
+    pub fn set_progress(&mut self, total: usize, bar: bool) {
+        self.progress = Some(Progress::new(total, bar));
+    }
+
+    pub fn set_failure_policy(&mut self, policy: FailurePolicy) {
+        self.on_error = policy;
+    }
+
+    // Hash of the source together with the encoder, its version and arguments,
+    // or None when nothing can be reused
+    fn page_key(&mut self, path: &Path, format: ImageFormat) -> Result<Option<String>> {
+        if self.cache.is_none() && self.state.is_none() {
+            return Ok(None);
+        }
+        let encoder = self.encoder;
+        if !self.versions.contains_key(encoder.program()) {
+            let version = encoder.version();
+            if version.is_none() {
+                self.warn(&format!(
+                    "Encoded pages are not reused, as '{}' could not be identified",
+                    encoder.program()
+                ));
+            }
+            self.versions.insert(encoder.program(), version);
+        }
+        let Some(version) = &self.versions[encoder.program()] else {
+            return Ok(None);
+        };
+        let mut hash = Sha256::new();
+        for part in [encoder.program(), version.as_str()].into_iter().chain(
+            encoder
+                .args(&self.settings, format)
+                .iter()
+                .map(String::as_str),
+        ) {
+            hash.update(part.as_bytes());
+            hash.update(&[0]);
+        }
+        std::io::copy(&mut File::open(path)?, &mut hash)?;
+        Ok(Some(hash.hex()))
+    }
+
+    // Write out finished pages and wait for both a free encoder and queue space
+    fn make_room(&mut self) -> CbtResult<()> {
+        loop {
+            check_signal()?;
+            self.write_ready()?;
+            let mut running = 0;
+            for job in &mut self.jobs {
+                if let CbtWriterJob::Convert(conversion, _) = job
+                    && conversion.process.try_wait()?.is_none()
+                {
+                    running += 1;
+                }
+            }
+            let max_ahead = self
+                .max_ahead
+                .unwrap_or(self.processes * Self::AHEAD_PER_PROCESS);
+            if running < self.processes && self.jobs.len() < max_ahead {
+                return Ok(());
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    // Jobs at the front of the queue that no longer wait on an encoder, in page order
+    fn write_ready(&mut self) -> CbtResult<()> {
+        while let Some(job) = self.jobs.front_mut() {
+            if let CbtWriterJob::Convert(conversion, _) = job
+                && conversion.process.try_wait()?.is_none()
+            {
+                break;
+            }
+            let job = self.jobs.pop_front().unwrap();
+            self.complete_job(job)?;
+        }
+        Ok(())
+    }
+
+    fn complete_job(&mut self, job: CbtWriterJob) -> CbtResult<()> {
+        let (path, name, temporary, source_len) = match job {
+            CbtWriterJob::Directory(name) => return Ok(self.archive.write_dir(&name)?),
+            CbtWriterJob::Copy(path, name, source_len) => (path, name, false, source_len),
+            CbtWriterJob::Duplicate {
+                page,
+                input,
+                format,
+                key,
+                name,
+            } => {
+                let target = self.encoder.target().unwrap_or(format);
+                let source_len = input.metadata()?.len();
+                match self.lookup(&key, target) {
+                    Some(done) if self.worth_keeping(&input, &done)? => {
+                        (done, name, false, source_len)
+                    }
+                    Some(_) => (
+                        input,
+                        page_name_with_format(&name, format),
+                        false,
+                        source_len,
+                    ),
+                    // Nothing kept from the earlier conversion, so convert it after all
+                    None => {
+                        let conversion = self.spawn(page, &input, format, target, Some(key))?;
+                        return self.complete_job(CbtWriterJob::Convert(conversion, name));
+                    }
+                }
+            }
+            CbtWriterJob::Convert(mut conversion, name) => {
+                let status = conversion.wait()?;
+                self.emit(CbtEvent::EncoderFinished {
+                    page: conversion.page,
+                    status,
+                    elapsed: conversion.started.elapsed(),
+                });
+                if !status.success() {
+                    return self.fail_conversion(conversion, status, name);
+                }
+                fs::remove_file(&conversion.log)?;
+                if let (Some(cache), Some(key)) = (&self.cache, &conversion.key)
+                    && let Some(target) = self.encoder.target()
+                    && let Err(err) = cache.store(key, target, &conversion.output)
+                {
+                    self.warn(&format!("Could not add page to cache: {err}"));
+                }
+
+                // Pages in the state directory stay there until the build is done
+                let (output, temporary) = match (&mut self.state, &conversion.key) {
+                    (Some(state), Some(key)) => (state.add(key, &conversion.output)?, false),
+                    _ => (conversion.output.clone(), true),
+                };
+                let source_len = conversion.input.metadata()?.len();
+                if !self.worth_keeping(&conversion.input, &output)? {
+                    if temporary {
+                        fs::remove_file(&output)?;
+                    }
+                    let name = page_name_with_format(&name, conversion.format);
+                    (conversion.input.clone(), name, false, source_len)
+                } else {
+                    (output, name, temporary, source_len)
+                }
+            }
+        };
+        let offset = self.archive.write_file(&path, &name)?;
+        let size = path.metadata()?.len();
+        self.completed += 1;
+        self.emit(CbtEvent::PageWritten {
+            page: self.completed,
+            name: &name,
+            offset,
+            size,
+        });
+        if let Some(progress) = &mut self.progress {
+            progress.page(&name, source_len, size);
+        }
+        if self.comic_info.is_some() {
+            self.pages.push(PageInfo {
+                size,
+                dimensions: image_dimensions(&path),
+                bookmark: None,
+            });
+        }
+        if temporary {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    // Converted page must be smaller than the original by the minimum savings
+    fn worth_keeping(&self, original: &Path, converted: &Path) -> Result<bool> {
+        let Some(min_savings) = self.min_savings else {
+            return Ok(true);
+        };
+        let original = original.metadata()?.len();
+        let converted = converted.metadata()?.len();
+        Ok(converted * 100 < original * (100 - min_savings as u64))
+    }
+
+    pub(crate) fn warn(&mut self, message: &str) {
+        if self.events.is_some() {
+            return self.emit(CbtEvent::Warning(message));
+        }
+        if let Some(progress) = &self.progress {
+            progress.clear();
+        }
+        eprintln!("WARNING: {message}");
+    }
+
+    fn fail_conversion(
+        &mut self,
+        conversion: Conversion,
+        status: ExitStatus,
+        name: String,
+    ) -> CbtResult<()> {
+        let error = CbtError::Encoder {
+            program: self.encoder.program(),
+            page: conversion.page,
+            input: conversion.input.clone(),
+            status,
+            stderr: conversion.stderr(),
+        };
+        if self.on_error == FailurePolicy::Abort {
+            return Err(error);
+        }
+        self.warn(&error.to_string());
+        fs::remove_file(&conversion.log)?;
+        if conversion.output.exists() {
+            fs::remove_file(&conversion.output)?;
+        }
+        let stored = self.on_error == FailurePolicy::Store;
+        self.failures.push(PageFailure {
+            page: conversion.page,
+            input: conversion.input.clone(),
+            status,
+            stored,
+        });
+
+        if stored {
+            let name = page_name_with_format(&name, conversion.format);
+            let source_len = conversion.input.metadata()?.len();
+            let job = CbtWriterJob::Copy(conversion.input.clone(), name, source_len);
+            return self.complete_job(job);
+        }
+        self.completed += 1;
+        if let Some(progress) = &mut self.progress {
+            progress.page(&name, conversion.input.metadata()?.len(), 0);
+        }
+        self.skipped.push(conversion.page - 1);
+        Ok(())
+    }
+}
+
+// Unfinished builds report what they leave behind as warnings, so that they
+// reach the event handler
+impl Drop for CbtWriter {
+    fn drop(&mut self) {
+        // Encoders are stopped before their files are removed
+        self.jobs.clear();
+        if let Some(dir) = self.state.as_ref().and_then(StateDir::kept) {
+            let message = format!(
+                "Progress is kept in '{}', use --resume to continue",
+                dir.display()
+            );
+            self.warn(&message);
+        }
+        let work_dir = self.work_dir.path().to_path_buf();
+        if let Err(err) = self.work_dir.close() {
+            self.warn(&format!(
+                "Could not remove temporary directory '{}': {err}",
+                work_dir.display()
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use super::*;
+    use crate::files::SharedBuffer;
+
+    // Conversion by a shell script instead of an encoder, given the input and
+    // output paths as $1 and $2
+    fn submit_script(cbt: &mut CbtWriter, input: &Path, script: &str) {
+        cbt.make_room().unwrap();
+        let page = cbt.submitted + 1;
+        let output = cbt.work_dir.path().join(format!("{page}.avif"));
+        let log = cbt.work_dir.path().join(format!("{page}.log"));
+        let process = Command::new("sh")
+            .args(["-c", script, "sh"])
+            .arg(input)
+            .arg(&output)
+            .stderr(File::create(&log).unwrap())
+            .spawn()
+            .unwrap();
+        let conversion = Conversion {
+            process,
+            page,
+            input: input.to_path_buf(),
+            format: ImageFormat::Png,
+            output,
+            log,
+            key: None,
+            started: Instant::now(),
+        };
+        let mut name = format!("{:0fill$}.avif", cbt.index, fill = cbt.padding);
+        if !cbt.chapter.is_empty() {
+            name = format!("{}/{name}", cbt.chapter);
+        }
+        cbt.jobs.push_back(CbtWriterJob::Convert(conversion, name));
+        cbt.index += 1;
+        cbt.submitted += 1;
+    }
+
+    fn tar_names(data: &[u8]) -> Vec<String> {
+        let mut reader = SimpleTarReader::new(data);
+        let mut names = Vec::new();
+        while let Some(entry) = reader.next_entry().unwrap() {
+            names.push(entry.name);
+        }
+        names
+    }
+
+    struct Fixture {
+        dir: TempDir,
+        archive: PathBuf,
+    }
+
+    impl Fixture {
+        // Existing archive with empty entries of the given names
+        fn new(names: &[&str]) -> Self {
+            let dir = TempDir::new("mkcbt-test").unwrap();
+            let archive = dir.path().join("a.cbt");
+            let mut tar = SimpleTarArchive::new(File::create(&archive).unwrap());
+            for name in names {
+                match name.strip_suffix('/') {
+                    Some(name) => tar.write_dir(name).unwrap(),
+                    None => tar.write_bytes(b"old", name).map(|_| ()).unwrap(),
+                }
+            }
+            tar.finish().unwrap();
+            Self { dir, archive }
+        }
+
+        fn page(&self, name: &str) -> PathBuf {
+            let path = self.dir.path().join(name);
+            fs::write(&path, b"new").unwrap();
+            path
+        }
+
+        // Pages added in the given chapters, with the page file names
+        fn append(&self, pages: &[(&str, &str)]) -> CbtResult<()> {
+            let mut cbt = CbtWriter::append(&self.archive, pages.iter().map(|x| x.0))?;
+            cbt.set_encoder(Encoder::Passthrough, EncoderSettings::default());
+            for (chapter, name) in pages {
+                cbt.set_chapter(chapter);
+                let path = match *name {
+                    "missing" => self.dir.path().join(name),
+                    _ => self.page(name),
+                };
+                cbt.submit(&path, ImageFormat::Png)?;
+            }
+            cbt.finish()?;
+            Ok(())
+        }
+
+        fn names(&self) -> Vec<String> {
+            let mut reader = SimpleTarReader::new(File::open(&self.archive).unwrap());
+            let mut names = Vec::new();
+            while let Some(entry) = reader.next_entry().unwrap() {
+                names.push(entry.name);
+            }
+            names
+        }
+    }
+
+    #[test]
+    fn page_numbers() {
+        assert_eq!(page_number("007.avif"), Some(("", 7, 3)));
+        assert_eq!(page_number("Chapter 1/10.png"), Some(("Chapter 1", 10, 2)));
+        assert_eq!(page_number("a/b/1.jpg"), Some(("a/b", 1, 1)));
+        assert_eq!(page_number("cover.png"), None);
+        assert_eq!(page_number("1a.png"), None);
+        assert_eq!(page_number(".png"), None);
+        assert_eq!(page_number("12"), None);
+        assert_eq!(
+            page_name_with_format("c/01.avif", ImageFormat::Png),
+            "c/01.png"
+        );
+    }
+
+    #[test]
+    fn append_continues_each_chapter() {
+        let fixture = Fixture::new(&["1.png", "Ch 1/", "Ch 1/1.png", "Ch 1/2.png", "Ch 2/1.png"]);
+        fixture
+            .append(&[("", "a.png"), ("Ch 1", "b.png"), ("Ch 3", "c.png")])
+            .unwrap();
+        assert_eq!(
+            fixture.names(),
+            [
+                "1.png",
+                "Ch 1/",
+                "Ch 1/1.png",
+                "Ch 1/2.png",
+                "Ch 2/1.png",
+                "2.png",
+                "Ch 1/3.png",
+                "Ch 3/",
+                "Ch 3/1.png",
+            ]
+        );
+    }
+
+    #[test]
+    fn append_repads_numbers() {
+        let names: Vec<String> = (1..=9).map(|i| format!("{i}.png")).collect();
+        let mut existing: Vec<&str> = names.iter().map(String::as_str).collect();
+        existing.extend(["cover.jpg", "Ch 1/1.png"]);
+        let fixture = Fixture::new(&existing);
+        fixture.append(&[("", "a.png")]).unwrap();
+
+        let mut expected: Vec<String> = (1..=9).map(|i| format!("0{i}.png")).collect();
+        expected.extend(["cover.jpg", "Ch 1/01.png", "10.png"].map(String::from));
+        assert_eq!(fixture.names(), expected);
+    }
+
+    #[test]
+    fn append_without_page_numbers() {
+        let fixture = Fixture::new(&["cover.png", "notes.txt", "ComicInfo.xml", "extra/0x1.png"]);
+        fixture.append(&[("", "a.png"), ("", "b.png")]).unwrap();
+        assert_eq!(
+            fixture.names()[4..],
+            ["1.png".to_string(), "2.png".to_string()]
+        );
+    }
+
+    #[test]
+    fn append_rolled_back_on_error() {
+        for existing in [&["1.png", "2.png"][..], &["1.png", "9.png"]] {
+            let fixture = Fixture::new(existing);
+            let original = fs::read(&fixture.archive).unwrap();
+            let pages = [("", "a.png"), ("", "b.png"), ("", "missing")];
+            assert!(fixture.append(&pages).is_err());
+            assert_eq!(fs::read(&fixture.archive).unwrap(), original);
+            assert_eq!(fs::read_dir(fixture.dir.path()).unwrap().count(), 3);
+        }
+    }
+
+    #[test]
+    fn summary_counts_written_pages() {
+        let fixture = Fixture::new(&[]);
+        for (policy, pages, names) in [
+            (FailurePolicy::Skip, 2, &["1.avif", "3.avif"][..]),
+            (FailurePolicy::Store, 3, &["1.avif", "2.png", "3.avif"]),
+        ] {
+            let buffer = SharedBuffer::default();
+            let mut cbt = CbtWriter::new(buffer.clone(), ArchiveFormat::Cbt, 1).unwrap();
+            cbt.set_failure_policy(policy);
+            let input = fixture.page("a.png");
+            submit_script(&mut cbt, &input, "cp \"$1\" \"$2\"");
+            submit_script(&mut cbt, &input, "exit 3");
+            submit_script(&mut cbt, &input, "cp \"$1\" \"$2\"");
+            let (_, summary) = cbt.finish().unwrap();
+            assert_eq!(summary.pages, pages);
+            assert_eq!(summary.failures.len(), 1);
+            assert_eq!(summary.failures[0].page, 2);
+            assert_eq!(tar_names(&buffer.take()), names);
+        }
+    }
+
+    #[test]
+    fn bookmarks_follow_written_pages() {
+        let fixture = Fixture::new(&[]);
+        let buffer = SharedBuffer::default();
+        let mut cbt = CbtWriter::new(buffer.clone(), ArchiveFormat::Cbt, 1).unwrap();
+        cbt.set_failure_policy(FailurePolicy::Skip);
+        let mut comic_info = ComicInfo::new();
+        comic_info.bookmarks = true;
+        cbt.set_comic_info(comic_info);
+        let input = fixture.page("a.png");
+        for (chapter, script) in [
+            ("A", "cp \"$1\" \"$2\""),
+            ("A", "exit 3"),
+            ("B", "cp \"$1\" \"$2\""),
+        ] {
+            cbt.set_chapter(chapter);
+            submit_script(&mut cbt, &input, script);
+        }
+        cbt.finish().unwrap();
+
+        let data = buffer.take();
+        let mut reader = SimpleTarReader::new(&data[..]);
+        let mut xml = Vec::new();
+        while let Some(entry) = reader.next_entry().unwrap() {
+            if entry.name == "ComicInfo.xml" {
+                reader.copy_data(&entry, &mut xml).unwrap();
+            }
+        }
+        assert!(String::from_utf8(xml).unwrap().contains(concat!(
+            "  <Pages>\n",
+            "    <Page Image=\"0\" Type=\"FrontCover\" ImageSize=\"3\" Bookmark=\"A\" />\n",
+            "    <Page Image=\"1\" ImageSize=\"3\" Bookmark=\"B\" />\n",
+            "  </Pages>\n",
+        )));
+        assert_eq!(
+            tar_names(&data),
+            ["A/", "A/1.avif", "B/", "B/1.avif", "ComicInfo.xml"]
+        );
+    }
+
+    // Encoder of the queued job at the given position
+    fn wait_for_encoder(cbt: &mut CbtWriter, index: usize) {
+        if let Some(CbtWriterJob::Convert(conversion, _)) = cbt.jobs.get_mut(index) {
+            conversion.process.wait().unwrap();
+        }
+    }
+
+    #[test]
+    fn pages_wait_for_earlier_ones() {
+        let fixture = Fixture::new(&[]);
+        let input = fixture.page("a.png");
+        let buffer = SharedBuffer::default();
+        let mut cbt = CbtWriter::new(buffer.clone(), ArchiveFormat::Cbt, 1).unwrap();
+        cbt.set_jobs(3);
+        submit_script(&mut cbt, &input, "sleep 0.3 && cp \"$1\" \"$2\"");
+        submit_script(&mut cbt, &input, "cp \"$1\" \"$2\"");
+        submit_script(&mut cbt, &input, "cp \"$1\" \"$2\"");
+        wait_for_encoder(&mut cbt, 1);
+        wait_for_encoder(&mut cbt, 2);
+
+        // Later pages are done, but the first is not
+        cbt.write_ready().unwrap();
+        assert_eq!((cbt.completed, cbt.jobs.len()), (0, 3));
+        wait_for_encoder(&mut cbt, 0);
+        cbt.write_ready().unwrap();
+        assert_eq!((cbt.completed, cbt.jobs.len()), (3, 0));
+        cbt.finish().unwrap();
+        assert_eq!(tar_names(&buffer.take()), ["1.avif", "2.avif", "3.avif"]);
+    }
+
+    #[test]
+    fn room_for_another_encoder() {
+        let fixture = Fixture::new(&[]);
+        let input = fixture.page("a.png");
+        let mut cbt = CbtWriter::new(std::io::sink(), ArchiveFormat::Cbt, 1).unwrap();
+        cbt.set_jobs(1);
+        submit_script(&mut cbt, &input, "sleep 0.2 && cp \"$1\" \"$2\"");
+        let started = Instant::now();
+        cbt.make_room().unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert_eq!((cbt.completed, cbt.jobs.len()), (1, 0));
+        cbt.finish().unwrap();
+    }
+
+    #[test]
+    fn room_in_the_queue() {
+        let fixture = Fixture::new(&[]);
+        let input = fixture.page("a.png");
+        let mut cbt = CbtWriter::new(std::io::sink(), ArchiveFormat::Cbt, 1).unwrap();
+        cbt.set_jobs(4);
+        cbt.set_max_ahead(2);
+        submit_script(&mut cbt, &input, "sleep 0.2 && cp \"$1\" \"$2\"");
+        submit_script(&mut cbt, &input, "cp \"$1\" \"$2\"");
+
+        // Encoders are free, but the queue is full until the first page is done
+        cbt.make_room().unwrap();
+        assert_eq!((cbt.completed, cbt.jobs.len()), (2, 0));
+        cbt.finish().unwrap();
+    }
+
+    #[test]
+    fn minimum_savings() {
+        let fixture = Fixture::new(&[]);
+        let original = fixture.dir.path().join("a.png");
+        let converted = fixture.dir.path().join("a.avif");
+        fs::write(&original, [0; 100]).unwrap();
+        let mut cbt = CbtWriter::new(std::io::sink(), ArchiveFormat::Cbt, 1).unwrap();
+        for (min_savings, len, keep) in [
+            (None, 150, true),
+            (Some(0), 99, true),
+            (Some(0), 100, false),
+            (Some(10), 89, true),
+            (Some(10), 90, false),
+            (Some(100), 0, false),
+        ] {
+            cbt.min_savings = min_savings;
+            fs::write(&converted, vec![0; len]).unwrap();
+            assert_eq!(
+                cbt.worth_keeping(&original, &converted).unwrap(),
+                keep,
+                "{min_savings:?} {len}"
+            );
+        }
+    }
+
+    #[test]
+    fn larger_pages_stored_as_they_are() {
+        let fixture = Fixture::new(&[]);
+        let input = fixture.page("a.png");
+        let buffer = SharedBuffer::default();
+        let mut cbt = CbtWriter::new(buffer.clone(), ArchiveFormat::Cbt, 1).unwrap();
+        cbt.set_keep_smaller(0);
+        submit_script(&mut cbt, &input, "head -c 2 \"$1\" > \"$2\"");
+        submit_script(&mut cbt, &input, "cat \"$1\" \"$1\" > \"$2\"");
+        cbt.finish().unwrap();
+        assert_eq!(tar_names(&buffer.take()), ["1.avif", "2.png"]);
+    }
+}
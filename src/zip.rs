@@ -0,0 +1,1141 @@
+//
+// Copyright 2024-2025 Christopher Atherton <the8lack8ox@pm.me>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the “Software”), to
+// deal in the Software without restriction, including without limitation the
+// rights to use, copy, modify, merge, publish, distribute, sublicense, and/or
+// sell copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+// THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS
+// IN THE SOFTWARE.
+//
+
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::files::Spool;
+
+// Basic ZIP files (stored, no compression)
+struct ZipEntry {
+    name: String,
+    crc: u32,
+    size: u64,
+    offset: u64,
+}
+
+pub(crate) struct SimpleZipArchive {
+    writer: Box<dyn Write>,
+    entries: Vec<ZipEntry>,
+    offset: u64,
+    finished: bool,
+}
+
+impl SimpleZipArchive {
+    const MAX_32: u64 = 0xffff_ffff;
+    const DOS_DATE: u16 = (1 << 5) | 1; // 1980-01-01
+
+    pub(crate) fn new(writer: impl Write + 'static) -> Self {
+        Self {
+            writer: Box::new(writer),
+            entries: Vec::new(),
+            offset: 0,
+            finished: false,
+        }
+    }
+
+    // Write the central directory and hand back the writer
+    pub(crate) fn finish(mut self) -> Result<Box<dyn Write>> {
+        self.finished = true;
+        self.write_end()?;
+        Ok(std::mem::replace(
+            &mut self.writer,
+            Box::new(std::io::sink()),
+        ))
+    }
+
+    fn write_end(&mut self) -> Result<()> {
+        let directory = self.central_directory();
+        self.writer.write_all(&directory)?;
+        self.writer
+            .write_all(&self.end_of_central_directory(directory.len() as u64))?;
+        self.writer.flush()
+    }
+
+    // Returns the offset of the file data
+    pub(crate) fn write_file<P: AsRef<Path>>(&mut self, path: P, file_name: &str) -> Result<u64> {
+        let file_len = path.as_ref().metadata()?.len();
+
+        // Stream can not be rewound, so get the CRC before writing the header
+        let mut crc = Crc32::new();
+        std::io::copy(&mut File::open(&path)?, &mut crc)?;
+        self.write_data(File::open(path)?, file_len, crc.value(), file_name)
+    }
+
+    pub(crate) fn write_bytes(&mut self, data: &[u8], file_name: &str) -> Result<u64> {
+        let mut crc = Crc32::new();
+        crc.update(data);
+        self.write_data(data, data.len() as u64, crc.value(), file_name)
+    }
+
+    // Reader must supply exactly the given length, and is spooled for the CRC
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn write_reader(
+        &mut self,
+        reader: impl Read,
+        len: u64,
+        file_name: &str,
+    ) -> Result<u64> {
+        let spool = Spool::new(reader.take(len))?;
+        let copied = spool.len()?;
+        if copied != len {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("'{file_name}' ended after {copied} of {len} bytes"),
+            ));
+        }
+        self.write_spool(spool, file_name)
+    }
+
+    // Not used by CbtWriter yet, like write_reader
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn write_stream(&mut self, reader: impl Read, file_name: &str) -> Result<u64> {
+        self.write_spool(Spool::new(reader)?, file_name)
+    }
+
+    fn write_spool(&mut self, mut spool: Spool, file_name: &str) -> Result<u64> {
+        let len = spool.len()?;
+        let mut crc = Crc32::new();
+        std::io::copy(&mut spool, &mut crc)?;
+        spool.rewind()?;
+        let offset = self.write_data(&mut spool, len, crc.value(), file_name)?;
+        spool.close()?;
+        Ok(offset)
+    }
+
+    fn write_data(
+        &mut self,
+        mut data: impl Read,
+        file_len: u64,
+        crc: u32,
+        file_name: &str,
+    ) -> Result<u64> {
+        // Write header
+        let header = Self::local_header(file_name, crc, file_len);
+        self.writer.write_all(&header)?;
+
+        // Copy data
+        let copied = std::io::copy(&mut data, &mut self.writer)?;
+        if copied != file_len {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("'{file_name}' changed size while being archived"),
+            ));
+        }
+
+        self.entries.push(ZipEntry {
+            name: file_name.to_string(),
+            crc,
+            size: file_len,
+            offset: self.offset,
+        });
+        self.offset += header.len() as u64 + file_len;
+
+        Ok(self.offset - file_len)
+    }
+
+    // Directories are empty entries with a trailing slash
+    pub(crate) fn write_dir(&mut self, dir_name: &str) -> Result<()> {
+        let name = format!("{}/", dir_name.trim_end_matches('/'));
+        let header = Self::local_header(&name, 0, 0);
+        self.writer.write_all(&header)?;
+        self.entries.push(ZipEntry {
+            name,
+            crc: 0,
+            size: 0,
+            offset: self.offset,
+        });
+        self.offset += header.len() as u64;
+        Ok(())
+    }
+
+    fn local_header(file_name: &str, crc: u32, file_len: u64) -> Vec<u8> {
+        let zip64 = file_len >= Self::MAX_32;
+        let mut header = Vec::with_capacity(30 + file_name.len() + 20);
+        header.extend_from_slice(&0x0403_4b50u32.to_le_bytes()); // Signature
+        header.extend_from_slice(&Self::version(file_name, zip64).to_le_bytes()); // Version
+        header.extend_from_slice(&Self::flags(file_name).to_le_bytes()); // Flags
+        header.extend_from_slice(&0u16.to_le_bytes()); // Compression (stored)
+        header.extend_from_slice(&0u16.to_le_bytes()); // Modification time
+        header.extend_from_slice(&Self::DOS_DATE.to_le_bytes()); // Modification date
+        header.extend_from_slice(&crc.to_le_bytes()); // CRC-32
+        header.extend_from_slice(&(file_len.min(Self::MAX_32) as u32).to_le_bytes()); // Compressed size
+        header.extend_from_slice(&(file_len.min(Self::MAX_32) as u32).to_le_bytes()); // Uncompressed size
+        header.extend_from_slice(&(file_name.len() as u16).to_le_bytes()); // Filename length
+        header.extend_from_slice(&(if zip64 { 20u16 } else { 0u16 }).to_le_bytes()); // Extra field length
+        header.extend_from_slice(file_name.as_bytes()); // Filename
+        if zip64 {
+            header.extend_from_slice(&1u16.to_le_bytes()); // ZIP64 extra field
+            header.extend_from_slice(&16u16.to_le_bytes());
+            header.extend_from_slice(&file_len.to_le_bytes());
+            header.extend_from_slice(&file_len.to_le_bytes());
+        }
+
+        header
+    }
+
+    fn version(file_name: &str, zip64: bool) -> u16 {
+        if zip64 {
+            45
+        } else if file_name.ends_with('/') {
+            20
+        } else {
+            10
+        }
+    }
+
+    // Set the UTF-8 flag for names that are not plain ASCII
+    fn flags(file_name: &str) -> u16 {
+        if file_name.is_ascii() { 0 } else { 1 << 11 }
+    }
+
+    fn central_directory(&self) -> Vec<u8> {
+        let mut directory = Vec::new();
+        for entry in &self.entries {
+            // Only the fields that overflow go into the ZIP64 extra field
+            let mut extra = Vec::new();
+            if entry.size >= Self::MAX_32 {
+                extra.extend_from_slice(&entry.size.to_le_bytes());
+                extra.extend_from_slice(&entry.size.to_le_bytes());
+            }
+            if entry.offset >= Self::MAX_32 {
+                extra.extend_from_slice(&entry.offset.to_le_bytes());
+            }
+            let version = Self::version(&entry.name, !extra.is_empty());
+            let attributes: u32 = if entry.name.ends_with('/') {
+                (0o40555 << 16) | 0x10
+            } else {
+                0o100444 << 16
+            };
+
+            directory.extend_from_slice(&0x0201_4b50u32.to_le_bytes()); // Signature
+            directory.extend_from_slice(&((3 << 8) | 45u16).to_le_bytes()); // Made by (UNIX)
+            directory.extend_from_slice(&version.to_le_bytes()); // Version
+            directory.extend_from_slice(&Self::flags(&entry.name).to_le_bytes()); // Flags
+            directory.extend_from_slice(&0u16.to_le_bytes()); // Compression (stored)
+            directory.extend_from_slice(&0u16.to_le_bytes()); // Modification time
+            directory.extend_from_slice(&Self::DOS_DATE.to_le_bytes()); // Modification date
+            directory.extend_from_slice(&entry.crc.to_le_bytes()); // CRC-32
+            directory.extend_from_slice(&(entry.size.min(Self::MAX_32) as u32).to_le_bytes()); // Compressed size
+            directory.extend_from_slice(&(entry.size.min(Self::MAX_32) as u32).to_le_bytes()); // Uncompressed size
+            directory.extend_from_slice(&(entry.name.len() as u16).to_le_bytes()); // Filename length
+            directory.extend_from_slice(
+                &(if extra.is_empty() {
+                    0
+                } else {
+                    extra.len() as u16 + 4
+                })
+                .to_le_bytes(),
+            ); // Extra field length
+            directory.extend_from_slice(&0u16.to_le_bytes()); // Comment length
+            directory.extend_from_slice(&0u16.to_le_bytes()); // Disk number
+            directory.extend_from_slice(&0u16.to_le_bytes()); // Internal attributes
+            directory.extend_from_slice(&attributes.to_le_bytes()); // External attributes
+            directory.extend_from_slice(&(entry.offset.min(Self::MAX_32) as u32).to_le_bytes()); // Local header offset
+            directory.extend_from_slice(entry.name.as_bytes()); // Filename
+            if !extra.is_empty() {
+                directory.extend_from_slice(&1u16.to_le_bytes()); // ZIP64 extra field
+                directory.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+                directory.extend_from_slice(&extra);
+            }
+        }
+        directory
+    }
+
+    fn end_of_central_directory(&self, directory_len: u64) -> Vec<u8> {
+        let count = self.entries.len() as u64;
+        let mut end = Vec::new();
+        if count >= 0xffff || directory_len >= Self::MAX_32 || self.offset >= Self::MAX_32 {
+            // ZIP64 end of central directory record
+            end.extend_from_slice(&0x0606_4b50u32.to_le_bytes()); // Signature
+            end.extend_from_slice(&44u64.to_le_bytes()); // Record size
+            end.extend_from_slice(&((3 << 8) | 45u16).to_le_bytes()); // Made by (UNIX)
+            end.extend_from_slice(&45u16.to_le_bytes()); // Version
+            end.extend_from_slice(&0u32.to_le_bytes()); // Disk number
+            end.extend_from_slice(&0u32.to_le_bytes()); // Central directory disk
+            end.extend_from_slice(&count.to_le_bytes()); // Entries on disk
+            end.extend_from_slice(&count.to_le_bytes()); // Total entries
+            end.extend_from_slice(&directory_len.to_le_bytes()); // Central directory size
+            end.extend_from_slice(&self.offset.to_le_bytes()); // Central directory offset
+
+            // ZIP64 end of central directory locator
+            end.extend_from_slice(&0x0706_4b50u32.to_le_bytes()); // Signature
+            end.extend_from_slice(&0u32.to_le_bytes()); // Disk number
+            end.extend_from_slice(&(self.offset + directory_len).to_le_bytes()); // Record offset
+            end.extend_from_slice(&1u32.to_le_bytes()); // Total disks
+        }
+        end.extend_from_slice(&0x0605_4b50u32.to_le_bytes()); // Signature
+        end.extend_from_slice(&0u16.to_le_bytes()); // Disk number
+        end.extend_from_slice(&0u16.to_le_bytes()); // Central directory disk
+        end.extend_from_slice(&(count.min(0xffff) as u16).to_le_bytes()); // Entries on disk
+        end.extend_from_slice(&(count.min(0xffff) as u16).to_le_bytes()); // Total entries
+        end.extend_from_slice(&(directory_len.min(Self::MAX_32) as u32).to_le_bytes()); // Central directory size
+        end.extend_from_slice(&(self.offset.min(Self::MAX_32) as u32).to_le_bytes()); // Central directory offset
+        end.extend_from_slice(&0u16.to_le_bytes()); // Comment length
+        end
+    }
+}
+
+// Best effort when not finished
+impl Drop for SimpleZipArchive {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.write_end();
+        }
+    }
+}
+
+// CRC-32 (ISO-HDLC) as used by ZIP
+struct Crc32 {
+    crc: u32,
+}
+
+impl Crc32 {
+    const TABLE: [u32; 256] = Self::table();
+
+    const fn table() -> [u32; 256] {
+        let mut table = [0; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut value = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                value = if value & 1 != 0 {
+                    0xedb8_8320 ^ (value >> 1)
+                } else {
+                    value >> 1
+                };
+                bit += 1;
+            }
+            table[i] = value;
+            i += 1;
+        }
+        table
+    }
+
+    fn new() -> Self {
+        Self { crc: 0xffff_ffff }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.crc = Self::TABLE[((self.crc ^ byte as u32) & 0xff) as usize] ^ (self.crc >> 8);
+        }
+    }
+
+    fn value(&self) -> u32 {
+        !self.crc
+    }
+}
+
+impl Write for Crc32 {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Reading ZIP files
+pub(crate) struct ZipReaderEntry {
+    pub(crate) name: String,
+    flags: u16,
+    method: u16,
+    crc: u32,
+    compressed_size: u64,
+    size: u64,
+    header_offset: u64,
+}
+
+pub(crate) struct SimpleZipReader<R: Read + Seek> {
+    reader: R,
+    pub(crate) entries: Vec<ZipReaderEntry>,
+}
+
+impl<R: Read + Seek> SimpleZipReader<R> {
+    pub(crate) fn new(mut reader: R) -> Result<Self> {
+        let bad_zip = |message: &str| Error::new(ErrorKind::InvalidData, message.to_string());
+
+        // End of central directory record is at the end, before the comment
+        let len = reader.seek(SeekFrom::End(0))?;
+        let tail_len = len.min(22 + 0xffff);
+        reader.seek(SeekFrom::Start(len - tail_len))?;
+        let mut tail = vec![0; tail_len as usize];
+        reader.read_exact(&mut tail)?;
+        let end = (0..tail.len().saturating_sub(21))
+            .rev()
+            .find(|&i| tail[i..i + 4] == 0x0605_4b50u32.to_le_bytes())
+            .ok_or_else(|| bad_zip("Missing ZIP end of central directory"))?;
+        let u16_at = |data: &[u8], i: usize| u16::from_le_bytes([data[i], data[i + 1]]);
+        let u32_at = |data: &[u8], i: usize| u32::from_le_bytes(data[i..i + 4].try_into().unwrap());
+        let u64_at = |data: &[u8], i: usize| u64::from_le_bytes(data[i..i + 8].try_into().unwrap());
+        let mut count = u16_at(&tail, end + 10) as u64;
+        let mut directory_len = u32_at(&tail, end + 12) as u64;
+        let mut directory_offset = u32_at(&tail, end + 16) as u64;
+
+        // ZIP64 end of central directory locator comes just before
+        if end >= 20 && tail[end - 20..end - 16] == 0x0706_4b50u32.to_le_bytes() {
+            reader.seek(SeekFrom::Start(u64_at(&tail, end - 12)))?;
+            let mut record = [0; 56];
+            reader.read_exact(&mut record)?;
+            if record[..4] != 0x0606_4b50u32.to_le_bytes() {
+                return Err(bad_zip("Bad ZIP64 end of central directory"));
+            }
+            count = u64_at(&record, 32);
+            directory_len = u64_at(&record, 40);
+            directory_offset = u64_at(&record, 48);
+        }
+
+        reader.seek(SeekFrom::Start(directory_offset))?;
+        let mut directory = Vec::new();
+        (&mut reader)
+            .take(directory_len)
+            .read_to_end(&mut directory)?;
+        let mut entries = Vec::new();
+        let mut i = 0;
+        for _ in 0..count {
+            if directory.len() < i + 46 || u32_at(&directory, i) != 0x0201_4b50 {
+                return Err(bad_zip("Bad ZIP central directory"));
+            }
+            let name_len = u16_at(&directory, i + 28) as usize;
+            let extra_len = u16_at(&directory, i + 30) as usize;
+            let comment_len = u16_at(&directory, i + 32) as usize;
+            let next = i + 46 + name_len + extra_len + comment_len;
+            if directory.len() < next {
+                return Err(bad_zip("Bad ZIP central directory"));
+            }
+            let mut entry = ZipReaderEntry {
+                name: String::from_utf8_lossy(&directory[i + 46..i + 46 + name_len]).into_owned(),
+                flags: u16_at(&directory, i + 8),
+                method: u16_at(&directory, i + 10),
+                crc: u32_at(&directory, i + 16),
+                compressed_size: u32_at(&directory, i + 20) as u64,
+                size: u32_at(&directory, i + 24) as u64,
+                header_offset: u32_at(&directory, i + 42) as u64,
+            };
+
+            // ZIP64 extra field holds the values that overflowed, in order
+            let mut extra = &directory[i + 46 + name_len..i + 46 + name_len + extra_len];
+            while extra.len() >= 4 {
+                let (id, len) = (u16_at(extra, 0), u16_at(extra, 2) as usize);
+                let data = &extra[4..(4 + len).min(extra.len())];
+                if id == 1 {
+                    let mut values = data.chunks_exact(8).map(|x| u64_at(x, 0));
+                    for field in [
+                        &mut entry.size,
+                        &mut entry.compressed_size,
+                        &mut entry.header_offset,
+                    ] {
+                        if *field == 0xffff_ffff {
+                            *field = values.next().unwrap_or(*field);
+                        }
+                    }
+                }
+                extra = &extra[(4 + len).min(extra.len())..];
+            }
+            entries.push(entry);
+            i = next;
+        }
+        Ok(Self { reader, entries })
+    }
+
+    pub(crate) fn read_data(&mut self, entry: &ZipReaderEntry) -> Result<Vec<u8>> {
+        let unsupported = |message: String| Error::new(ErrorKind::Unsupported, message);
+        if entry.flags & 1 != 0 {
+            return Err(unsupported(format!("'{}' is encrypted", entry.name)));
+        }
+
+        // Local header has its own name and extra field lengths
+        self.reader.seek(SeekFrom::Start(entry.header_offset))?;
+        let mut header = [0; 30];
+        self.reader.read_exact(&mut header)?;
+        if header[..4] != 0x0403_4b50u32.to_le_bytes() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Bad ZIP local header for '{}'", entry.name),
+            ));
+        }
+        let skip = u16::from_le_bytes([header[26], header[27]]) as i64
+            + u16::from_le_bytes([header[28], header[29]]) as i64;
+        self.reader.seek(SeekFrom::Current(skip))?;
+
+        let mut compressed = Vec::new();
+        (&mut self.reader)
+            .take(entry.compressed_size)
+            .read_to_end(&mut compressed)?;
+        let data = match entry.method {
+            0 => compressed,
+            8 => inflate(&compressed, entry.size)?,
+            method => {
+                return Err(unsupported(format!(
+                    "'{}' uses unsupported compression method {method}",
+                    entry.name
+                )));
+            }
+        };
+        let mut crc = Crc32::new();
+        crc.update(&data);
+        if data.len() as u64 != entry.size || crc.value() != entry.crc {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("'{}' is corrupt", entry.name),
+            ));
+        }
+        Ok(data)
+    }
+}
+
+// DEFLATE decompression (RFC 1951)
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl BitReader<'_> {
+    fn bits(&mut self, count: usize) -> Result<u32> {
+        let mut value = 0;
+        for i in 0..count {
+            let byte = self
+                .data
+                .get(self.position / 8)
+                .ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "Truncated DEFLATE stream"))?;
+            value |= (((byte >> (self.position % 8)) & 1) as u32) << i;
+            self.position += 1;
+        }
+        Ok(value)
+    }
+}
+
+// Canonical Huffman code as counts per length and symbols in code order
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut offsets = [0; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Self { counts, symbols }
+    }
+
+    fn decode(&self, bits: &mut BitReader) -> Result<u16> {
+        let (mut code, mut first, mut index) = (0, 0, 0);
+        for len in 1..16 {
+            code |= bits.bits(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - count < first {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(Error::new(ErrorKind::InvalidData, "Bad DEFLATE code"))
+    }
+}
+
+// Output is limited to the declared size, so that small entries can not
+// expand without bound
+fn inflate(data: &[u8], limit: u64) -> Result<Vec<u8>> {
+    const LENGTH_BASE: [u16; 29] = [
+        3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115,
+        131, 163, 195, 227, 258,
+    ];
+    const LENGTH_EXTRA: [u8; 29] = [
+        0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+    ];
+    const DISTANCE_BASE: [u16; 30] = [
+        1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+        2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+    ];
+    const DISTANCE_EXTRA: [u8; 30] = [
+        0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12,
+        13, 13,
+    ];
+    const CODE_LENGTH_ORDER: [usize; 19] = [
+        16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+    ];
+    let bad_stream = || Error::new(ErrorKind::InvalidData, "Bad DEFLATE stream");
+    let too_long = || {
+        Error::new(
+            ErrorKind::InvalidData,
+            "DEFLATE stream is longer than the declared size",
+        )
+    };
+
+    let mut bits = BitReader { data, position: 0 };
+    let mut output = Vec::new();
+    loop {
+        let last = bits.bits(1)? == 1;
+        let (literals, distances) = match bits.bits(2)? {
+            // Stored
+            0 => {
+                let start = bits.position.div_ceil(8);
+                let header = data.get(start..start + 4).ok_or_else(bad_stream)?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                if len != !u16::from_le_bytes([header[2], header[3]]) {
+                    return Err(bad_stream());
+                }
+                let block = data
+                    .get(start + 4..start + 4 + len as usize)
+                    .ok_or_else(bad_stream)?;
+                if (output.len() + block.len()) as u64 > limit {
+                    return Err(too_long());
+                }
+                output.extend_from_slice(block);
+                bits.position = (start + 4 + len as usize) * 8;
+                if last {
+                    return Ok(output);
+                }
+                continue;
+            }
+            // Fixed Huffman codes
+            1 => {
+                let mut lengths = [8; 288];
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+            }
+            // Dynamic Huffman codes
+            2 => {
+                let literal_count = bits.bits(5)? as usize + 257;
+                let distance_count = bits.bits(5)? as usize + 1;
+                let code_length_count = bits.bits(4)? as usize + 4;
+                let mut code_lengths = [0; 19];
+                for &i in &CODE_LENGTH_ORDER[..code_length_count] {
+                    code_lengths[i] = bits.bits(3)? as u8;
+                }
+                let code_lengths = Huffman::new(&code_lengths);
+                let mut lengths = Vec::with_capacity(literal_count + distance_count);
+                while lengths.len() < literal_count + distance_count {
+                    let (value, repeat) = match code_lengths.decode(&mut bits)? {
+                        symbol @ 0..=15 => (symbol as u8, 1),
+                        16 => (*lengths.last().ok_or_else(bad_stream)?, 3 + bits.bits(2)?),
+                        17 => (0, 3 + bits.bits(3)?),
+                        _ => (0, 11 + bits.bits(7)?),
+                    };
+                    lengths.extend(std::iter::repeat_n(value, repeat as usize));
+                }
+                if lengths.len() > literal_count + distance_count {
+                    return Err(bad_stream());
+                }
+                (
+                    Huffman::new(&lengths[..literal_count]),
+                    Huffman::new(&lengths[literal_count..]),
+                )
+            }
+            _ => return Err(bad_stream()),
+        };
+
+        loop {
+            let symbol = literals.decode(&mut bits)? as usize;
+            match symbol {
+                0..=255 if output.len() as u64 >= limit => return Err(too_long()),
+                0..=255 => output.push(symbol as u8),
+                256 => break,
+                _ => {
+                    let index = symbol - 257;
+                    if index >= LENGTH_BASE.len() {
+                        return Err(bad_stream());
+                    }
+                    let len = LENGTH_BASE[index] as usize
+                        + bits.bits(LENGTH_EXTRA[index] as usize)? as usize;
+                    let index = distances.decode(&mut bits)? as usize;
+                    if index >= DISTANCE_BASE.len() {
+                        return Err(bad_stream());
+                    }
+                    let distance = DISTANCE_BASE[index] as usize
+                        + bits.bits(DISTANCE_EXTRA[index] as usize)? as usize;
+                    if distance > output.len() {
+                        return Err(bad_stream());
+                    }
+                    if (output.len() + len) as u64 > limit {
+                        return Err(too_long());
+                    }
+                    let start = output.len() - distance;
+                    for i in 0..len {
+                        output.push(output[start + i]);
+                    }
+                }
+            }
+        }
+        if last {
+            return Ok(output);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::files::SharedBuffer;
+
+    fn crc(data: &[u8]) -> u32 {
+        let mut crc = Crc32::new();
+        crc.update(data);
+        crc.value()
+    }
+
+    const GIB: u64 = 1 << 30;
+
+    fn u16_at(data: &[u8], i: usize) -> u16 {
+        u16::from_le_bytes([data[i], data[i + 1]])
+    }
+
+    fn u32_at(data: &[u8], i: usize) -> u32 {
+        u32::from_le_bytes(data[i..i + 4].try_into().unwrap())
+    }
+
+    fn u64_at(data: &[u8], i: usize) -> u64 {
+        u64::from_le_bytes(data[i..i + 8].try_into().unwrap())
+    }
+
+    // Archive with entries at the given sizes and offsets, without their data
+    fn directory(entries: &[(u64, u64)]) -> SimpleZipArchive {
+        let mut zip = SimpleZipArchive::new(std::io::sink());
+        for (i, &(size, offset)) in entries.iter().enumerate() {
+            zip.entries.push(ZipEntry {
+                name: format!("{i}.png"),
+                crc: 0,
+                size,
+                offset,
+            });
+        }
+        zip
+    }
+
+    #[test]
+    fn crc32() {
+        assert_eq!(crc(b""), 0);
+        assert_eq!(crc(b"123456789"), 0xcbf4_3926);
+        assert_eq!(
+            crc(b"The quick brown fox jumps over the lazy dog"),
+            0x414f_a339
+        );
+        // Split updates match a single one
+        let mut split = Crc32::new();
+        split.update(b"1234");
+        split.update(b"56789");
+        assert_eq!(split.value(), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn stored_layout() {
+        let buffer = SharedBuffer::default();
+        let mut zip = SimpleZipArchive::new(buffer.clone());
+        assert_eq!(zip.write_bytes(b"hello", "a.png").unwrap(), 35);
+        zip.write_dir("dir/").unwrap();
+        assert_eq!(zip.write_bytes(b"", "dir/é.png").unwrap(), 114);
+        zip.finish().unwrap();
+        let data = buffer.take();
+
+        // Local header
+        assert_eq!(u32_at(&data, 0), 0x0403_4b50);
+        assert_eq!(u16_at(&data, 4), 10);
+        assert_eq!(u16_at(&data, 8), 0);
+        assert_eq!(u32_at(&data, 14), crc(b"hello"));
+        assert_eq!((u32_at(&data, 18), u32_at(&data, 22)), (5, 5));
+        assert_eq!(&data[30..40], b"a.pnghello");
+        assert_eq!(&data[70..74], b"dir/");
+        assert_eq!(u16_at(&data, 40 + 4), 20);
+        assert_eq!(u16_at(&data, 74 + 6), 1 << 11);
+
+        // End of central directory
+        let end = data.len() - 22;
+        assert_eq!(u32_at(&data, end), 0x0605_4b50);
+        assert_eq!(u16_at(&data, end + 10), 3);
+        let offset = u32_at(&data, end + 16) as usize;
+        assert_eq!(offset, 114);
+        assert_eq!(u32_at(&data, end + 12) as usize, end - offset);
+        assert_eq!(u32_at(&data, offset), 0x0201_4b50);
+    }
+
+    #[test]
+    fn zip64_local_header() {
+        let header = SimpleZipArchive::local_header("a.png", 7, 5 * GIB);
+        assert_eq!(header.len(), 30 + 5 + 20);
+        assert_eq!(u16_at(&header, 4), 45);
+        assert_eq!((u32_at(&header, 18), u32_at(&header, 22)), (!0, !0));
+        assert_eq!(u16_at(&header, 28), 20);
+        assert_eq!((u16_at(&header, 35), u16_at(&header, 37)), (1, 16));
+        assert_eq!(
+            (u64_at(&header, 39), u64_at(&header, 47)),
+            (5 * GIB, 5 * GIB)
+        );
+    }
+
+    #[test]
+    fn zip64_central_directory() {
+        // Only the overflowing fields are stored, sizes before offset
+        let zip = directory(&[(10, 0), (5 * GIB, 100), (10, 6 * GIB), (5 * GIB, 7 * GIB)]);
+        let directory = zip.central_directory();
+        let mut extras = Vec::new();
+        let mut i = 0;
+        while i < directory.len() {
+            let name_len = u16_at(&directory, i + 28) as usize;
+            let extra_len = u16_at(&directory, i + 30) as usize;
+            let extra = &directory[i + 46 + name_len..i + 46 + name_len + extra_len];
+            let values: Vec<u64> = extra
+                .get(4..)
+                .unwrap_or_default()
+                .chunks_exact(8)
+                .map(|x| u64_at(x, 0))
+                .collect();
+            extras.push((
+                u16_at(&directory, i + 6),
+                u32_at(&directory, i + 42),
+                values,
+            ));
+            i += 46 + name_len + extra_len;
+        }
+        assert_eq!(
+            extras,
+            [
+                (10, 0, vec![]),
+                (45, 100, vec![5 * GIB, 5 * GIB]),
+                (45, !0, vec![6 * GIB]),
+                (45, !0, vec![5 * GIB, 5 * GIB, 7 * GIB]),
+            ]
+        );
+    }
+
+    #[test]
+    fn zip64_end_of_central_directory() {
+        let zip = directory(&[(10, 0)]);
+        assert_eq!(zip.end_of_central_directory(100).len(), 22);
+
+        let mut zip = directory(&[(10, 0)]);
+        zip.offset = 5 * GIB;
+        let end = zip.end_of_central_directory(100);
+        assert_eq!(end.len(), 56 + 20 + 22);
+        assert_eq!(u32_at(&end, 0), 0x0606_4b50);
+        assert_eq!(
+            (u64_at(&end, 32), u64_at(&end, 40), u64_at(&end, 48)),
+            (1, 100, 5 * GIB)
+        );
+        assert_eq!(u32_at(&end, 56), 0x0706_4b50);
+        assert_eq!(u64_at(&end, 64), 5 * GIB + 100);
+        assert_eq!(u32_at(&end, 76 + 16), !0);
+    }
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    // Dynamic block vector from zlib at level 9
+    fn squares() -> (String, Vec<u8>) {
+        let text: Vec<String> = (0..60).map(|i| (i * i % 97).to_string()).collect();
+        let compressed = hex(concat!(
+            "1d8c870d044108c45a71094b86fe1bfbf9931022d8f33092c31a2fa2c9a39335",
+            "024f7218c709a39d1311ccc39c0a4eb45c05684eeab174e1c32e95b8b34a5354",
+            "61c6362d6309c74472c116738c7a7cf5ad3aead57f48a80469db3f",
+        ));
+        (text.join(" "), compressed)
+    }
+
+    #[test]
+    fn inflate_stored() {
+        assert_eq!(inflate(&hex("010500faff68656c6c6f"), 5).unwrap(), b"hello");
+        assert_eq!(inflate(&hex("010000ffff"), 0).unwrap(), b"");
+    }
+
+    #[test]
+    fn inflate_fixed() {
+        assert_eq!(
+            inflate(&hex("4b4c4a4e444500"), 18).unwrap(),
+            b"abcabcabcabcabcabc"
+        );
+        // Fixed block, empty stored block from a sync flush, then a final fixed block
+        assert_eq!(
+            inflate(&hex("4a4c4a06000000ffff4b042300"), 9).unwrap(),
+            b"abcabcabc"
+        );
+    }
+
+    #[test]
+    fn inflate_dynamic() {
+        let (text, compressed) = squares();
+        assert_eq!(compressed[0] >> 1 & 3, 2);
+        assert_eq!(
+            inflate(&compressed, text.len() as u64).unwrap(),
+            text.as_bytes()
+        );
+    }
+
+    #[test]
+    fn inflate_errors() {
+        let error = |data: &str| inflate(&hex(data), 100).unwrap_err();
+        // Length and its complement disagree
+        assert_eq!(
+            error("010500fbff68656c6c6f").to_string(),
+            "Bad DEFLATE stream"
+        );
+        assert_eq!(error("010500faff6865").to_string(), "Bad DEFLATE stream");
+        // Reserved block type
+        assert_eq!(error("07").to_string(), "Bad DEFLATE stream");
+        // Distance before the start of the output
+        assert_eq!(error("4b044200").to_string(), "Bad DEFLATE stream");
+        assert_eq!(error("4b4c4a4e").kind(), ErrorKind::UnexpectedEof);
+    }
+
+    // Stored data, literals and copies each stop at the declared size
+    #[test]
+    fn inflate_limited() {
+        let (text, compressed) = squares();
+        for (data, len) in [
+            (hex("010500faff68656c6c6f"), 5),
+            (hex("4b4c4a4e444500"), 18),
+            (hex("4b4c4a4e444500"), 2),
+            (compressed, text.len() as u64),
+        ] {
+            let error = inflate(&data, len - 1).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidData);
+            assert_eq!(
+                error.to_string(),
+                "DEFLATE stream is longer than the declared size"
+            );
+        }
+    }
+
+    // Central directory entry fields for a hand-built archive, the flags
+    // selecting which of size, compressed size and offset overflow
+    struct RawEntry<'a> {
+        name: &'a str,
+        method: u16,
+        crc: u32,
+        data: &'a [u8],
+        size: u64,
+        zip64: [bool; 3],
+    }
+
+    fn raw_archive(entries: &[RawEntry], zip64_end: bool) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut directory = Vec::new();
+        for entry in entries {
+            let offset = data.len() as u64;
+            data.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+            data.extend_from_slice(&[20, 0, 0, 0]);
+            data.extend_from_slice(&entry.method.to_le_bytes());
+            data.extend_from_slice(&[0; 16]);
+            data.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+            data.extend_from_slice(&3u16.to_le_bytes());
+            data.extend_from_slice(entry.name.as_bytes());
+            data.extend_from_slice(b"xyz");
+            data.extend_from_slice(entry.data);
+
+            let mut fields = [entry.size, entry.data.len() as u64, offset];
+            let mut extra = Vec::new();
+            for (field, zip64) in fields.iter_mut().zip(entry.zip64) {
+                if zip64 {
+                    extra.extend_from_slice(&field.to_le_bytes());
+                    *field = 0xffff_ffff;
+                }
+            }
+            directory.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+            directory.extend_from_slice(&[45, 3, 45, 0, 0, 0]);
+            directory.extend_from_slice(&entry.method.to_le_bytes());
+            directory.extend_from_slice(&[0; 4]);
+            directory.extend_from_slice(&entry.crc.to_le_bytes());
+            directory.extend_from_slice(&(fields[1] as u32).to_le_bytes());
+            directory.extend_from_slice(&(fields[0] as u32).to_le_bytes());
+            directory.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+            // An unrelated extra field comes first
+            directory.extend_from_slice(&(extra.len() as u16 + 4 + 6).to_le_bytes());
+            directory.extend_from_slice(&[0; 10]);
+            directory.extend_from_slice(&(fields[2] as u32).to_le_bytes());
+            directory.extend_from_slice(entry.name.as_bytes());
+            directory.extend_from_slice(&[0x55, 0x54, 2, 0, 0, 0]);
+            directory.extend_from_slice(&1u16.to_le_bytes());
+            directory.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+            directory.extend_from_slice(&extra);
+        }
+
+        let (count, offset) = (entries.len() as u64, data.len() as u64);
+        let directory_len = directory.len() as u64;
+        data.extend_from_slice(&directory);
+        if zip64_end {
+            let record = data.len() as u64;
+            data.extend_from_slice(&0x0606_4b50u32.to_le_bytes());
+            data.extend_from_slice(&[0; 28]);
+            for value in [count, directory_len, offset] {
+                data.extend_from_slice(&value.to_le_bytes());
+            }
+            data.extend_from_slice(&0x0706_4b50u32.to_le_bytes());
+            data.extend_from_slice(&[0; 4]);
+            data.extend_from_slice(&record.to_le_bytes());
+            data.extend_from_slice(&1u32.to_le_bytes());
+        }
+        data.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+        data.extend_from_slice(&[0; 6]);
+        let count = if zip64_end { 0xffff } else { count as u16 };
+        data.extend_from_slice(&count.to_le_bytes());
+        let (directory_len, offset) = if zip64_end {
+            (!0, !0)
+        } else {
+            (directory_len as u32, offset as u32)
+        };
+        data.extend_from_slice(&directory_len.to_le_bytes());
+        data.extend_from_slice(&offset.to_le_bytes());
+        data.extend_from_slice(&[0; 2]);
+        data
+    }
+
+    fn read_all(data: Vec<u8>) -> Result<Vec<(String, Vec<u8>)>> {
+        let mut reader = SimpleZipReader::new(std::io::Cursor::new(data))?;
+        let entries = std::mem::take(&mut reader.entries);
+        entries
+            .iter()
+            .map(|entry| Ok((entry.name.clone(), reader.read_data(entry)?)))
+            .collect()
+    }
+
+    #[test]
+    fn reader_round_trip() {
+        let buffer = SharedBuffer::default();
+        let mut zip = SimpleZipArchive::new(buffer.clone());
+        zip.write_bytes(b"hello", "a.png").unwrap();
+        zip.write_reader(&b"page, and more"[..], 4, "b.png")
+            .unwrap();
+        zip.write_dir("dir").unwrap();
+        zip.write_stream(&[7; 1000][..], "dir/ü.png").unwrap();
+        zip.finish().unwrap();
+        assert_eq!(
+            read_all(buffer.take()).unwrap(),
+            [
+                ("a.png".to_string(), b"hello".to_vec()),
+                ("b.png".to_string(), b"page".to_vec()),
+                ("dir/".to_string(), Vec::new()),
+                ("dir/ü.png".to_string(), vec![7; 1000]),
+            ]
+        );
+    }
+
+    #[test]
+    fn reader_deflate() {
+        let (text, compressed) = squares();
+        let archive = |size| {
+            raw_archive(
+                &[RawEntry {
+                    name: "squares.txt",
+                    method: 8,
+                    crc: 0xcc4b_a273,
+                    data: &compressed,
+                    size,
+                    zip64: [false; 3],
+                }],
+                false,
+            )
+        };
+        let len = text.len() as u64;
+        assert_eq!(read_all(archive(len)).unwrap()[0].1, text.as_bytes());
+        // Declared size understates the data
+        assert_eq!(
+            read_all(archive(len - 1)).unwrap_err().to_string(),
+            "DEFLATE stream is longer than the declared size"
+        );
+    }
+
+    #[test]
+    fn reader_zip64_extra_order() {
+        let (text, compressed) = squares();
+        let entry = |name, zip64| RawEntry {
+            name,
+            method: 8,
+            crc: 0xcc4b_a273,
+            data: &compressed,
+            size: text.len() as u64,
+            zip64,
+        };
+        // Sizes differ, so taking them out of order reads the wrong amount
+        let data = raw_archive(
+            &[
+                entry("all", [true; 3]),
+                entry("sizes", [true, true, false]),
+                entry("compressed", [false, true, false]),
+                entry("offset", [false, false, true]),
+                entry("size and offset", [true, false, true]),
+            ],
+            true,
+        );
+        for (name, content) in read_all(data).unwrap() {
+            assert_eq!(content, text.as_bytes(), "{name}");
+        }
+    }
+
+    #[test]
+    fn reader_errors() {
+        let entry = |method, crc, flags: bool| {
+            let mut data = raw_archive(
+                &[RawEntry {
+                    name: "a.png",
+                    method,
+                    crc,
+                    data: b"hello",
+                    size: 5,
+                    zip64: [false; 3],
+                }],
+                false,
+            );
+            if flags {
+                let directory = data.len() - 22 - 46 - 5 - 10;
+                data[directory + 8] = 1;
+            }
+            read_all(data).unwrap_err()
+        };
+        let hello = crc(b"hello");
+        assert_eq!(entry(0, hello ^ 1, false).to_string(), "'a.png' is corrupt");
+        assert_eq!(entry(0, hello ^ 1, false).kind(), ErrorKind::InvalidData);
+        assert_eq!(
+            entry(12, hello, false).to_string(),
+            "'a.png' uses unsupported compression method 12"
+        );
+        assert_eq!(entry(0, hello, true).to_string(), "'a.png' is encrypted");
+
+        let error = SimpleZipReader::new(std::io::Cursor::new(vec![0; 100])).err();
+        assert_eq!(
+            error.unwrap().to_string(),
+            "Missing ZIP end of central directory"
+        );
+    }
+}